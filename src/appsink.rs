@@ -0,0 +1,411 @@
+use crate::pipeline_builder::ElementSpec;
+use byte_slice_cast::AsSliceOf;
+use bytes::{Bytes, BytesMut};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use gstreamer::{element_error, Buffer, FlowError, Sample};
+use gstreamer_app::{AppSink, AppSinkCallbacks};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// What happens to a buffer when the channel to the consumer is full
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DropPolicy {
+    // wait for the consumer, holding up the pipeline's streaming thread
+    Block,
+
+    // make room by dropping the oldest buffer in the channel
+    DropOldest,
+
+    // drop the buffer that does not fit
+    DropNewest,
+}
+
+// How buffers of an appsink pipeline are handed to the consumer
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppsinkOptions {
+    // None for an unbounded channel
+    capacity: Option<usize>,
+    policy: DropPolicy,
+
+    // buffers queued in the appsink itself, 0 for no limit
+    max_buffers: u32,
+
+    // drop the oldest buffers in the appsink instead of blocking when max_buffers is reached
+    drop: bool,
+}
+
+impl Default for AppsinkOptions {
+    fn default() -> Self {
+        AppsinkOptions {
+            capacity: Some(100),
+            policy: DropPolicy::Block,
+            max_buffers: 0,
+            drop: false,
+        }
+    }
+}
+
+impl AppsinkOptions {
+    pub fn new() -> Self {
+        AppsinkOptions::default()
+    }
+
+    // Buffers the channel holds before the drop policy applies.  Like
+    // max_buffers, 0 is no limit: the same as `unbounded`, not a channel that
+    // blocks every buffer until the consumer takes it.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity).filter(|&capacity| capacity > 0);
+        self
+    }
+
+    // Let the channel grow without limit, the drop policy never applies
+    pub fn unbounded(mut self) -> Self {
+        self.capacity = None;
+        self
+    }
+
+    pub fn policy(mut self, policy: DropPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn max_buffers(mut self, max_buffers: u32) -> Self {
+        self.max_buffers = max_buffers;
+        self
+    }
+
+    // Buffers the appsink drops are not counted, GStreamer does not report them
+    pub fn drop(mut self, drop: bool) -> Self {
+        self.drop = drop;
+        self
+    }
+
+    pub(crate) fn appsink(&self, name: &str) -> ElementSpec {
+        let mut appsink = ElementSpec::new("appsink").name(name);
+
+        if self.max_buffers > 0 {
+            appsink = appsink.property("max-buffers", self.max_buffers);
+        }
+
+        if self.drop {
+            appsink = appsink.property("drop", true);
+        }
+
+        appsink
+    }
+
+    // The channel between the appsink and the consumer
+    pub(crate) fn channel<T>(&self) -> (BufferSender<T>, BufferReceiver<T>) {
+        let (sender, receiver) = match self.capacity {
+            Some(capacity) => bounded::<T>(capacity),
+            None => unbounded::<T>(),
+        };
+        let consumer = Arc::new(());
+        let buffer_sender = BufferSender {
+            sender,
+            // to drop the oldest buffer, the sending side takes it off the channel
+            oldest: match self.policy {
+                DropPolicy::DropOldest => Some(receiver.clone()),
+                _ => None,
+            },
+            policy: self.policy,
+            dropped: DroppedBuffers::default(),
+            consumer: consumer.clone(),
+        };
+
+        (buffer_sender, BufferReceiver { receiver, consumer })
+    }
+}
+
+// The consumer's end of the channel of an appsink pipeline, used like the
+// receiver it derefs to.  Once it and its clones are dropped the pipeline
+// stops, with any drop policy.
+pub struct BufferReceiver<T> {
+    receiver: Receiver<T>,
+
+    // shared with the sender, which counts it to notice the consumer is gone
+    consumer: Arc<()>,
+}
+
+impl<T> BufferReceiver<T> {
+    // The receiver itself, only for channels the sender holds no receiver of
+    pub(crate) fn into_receiver(self) -> Receiver<T> {
+        self.receiver
+    }
+}
+
+impl<T> Clone for BufferReceiver<T> {
+    fn clone(&self) -> Self {
+        BufferReceiver {
+            receiver: self.receiver.clone(),
+            consumer: self.consumer.clone(),
+        }
+    }
+}
+
+impl<T> Deref for BufferReceiver<T> {
+    type Target = Receiver<T>;
+
+    fn deref(&self) -> &Receiver<T> {
+        &self.receiver
+    }
+}
+
+impl<'a, T> IntoIterator for &'a BufferReceiver<T> {
+    type Item = T;
+    type IntoIter = crossbeam_channel::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.receiver.iter()
+    }
+}
+
+// The number of buffers dropped on the way to the consumer
+#[derive(Clone, Debug, Default)]
+pub struct DroppedBuffers(Arc<AtomicU64>);
+
+impl DroppedBuffers {
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn add(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Sends buffers to the consumer following the drop policy
+pub(crate) struct BufferSender<T> {
+    sender: Sender<T>,
+    oldest: Option<Receiver<T>>,
+    policy: DropPolicy,
+    dropped: DroppedBuffers,
+    consumer: Arc<()>,
+}
+
+impl<T> BufferSender<T> {
+    pub(crate) fn sender(&self) -> Sender<T> {
+        self.sender.clone()
+    }
+
+    pub(crate) fn dropped(&self) -> DroppedBuffers {
+        self.dropped.clone()
+    }
+
+    // Send a buffer, false once the consumer is gone
+    pub(crate) fn send(&self, bytes: T) -> bool {
+        // the receiver held for dropping the oldest buffer keeps the channel
+        // connected, so the consumer is counted instead
+        if self.oldest.is_some() && Arc::strong_count(&self.consumer) == 1 {
+            return false;
+        }
+
+        let bytes = match self.sender.try_send(bytes) {
+            Ok(()) => return true,
+            Err(TrySendError::Disconnected(_)) => return false,
+            Err(TrySendError::Full(bytes)) => bytes,
+        };
+
+        match (self.policy, &self.oldest) {
+            (DropPolicy::DropOldest, Some(oldest)) => {
+                if oldest.try_recv().is_ok() {
+                    self.dropped.add();
+                }
+
+                if self.sender.try_send(bytes).is_err() {
+                    self.dropped.add();
+                }
+
+                true
+            }
+            (DropPolicy::DropNewest, _) => {
+                self.dropped.add();
+                true
+            }
+            _ => self.sender.send(bytes).is_ok(),
+        }
+    }
+}
+
+// Turns a sample the appsink received into what is sent to the consumer
+pub(crate) type Convert<T> = fn(&AppSink, Sample) -> std::result::Result<T, FlowError>;
+
+// Have the appsink convert each sample and hand it to `deliver`, which returns
+// false once nobody takes them anymore to end the stream
+pub(crate) fn set_sample_callbacks<T, D>(appsink: &AppSink, convert: Convert<T>, mut deliver: D)
+where
+    D: FnMut(T) -> bool + Send + 'static,
+    T: 'static,
+{
+    // Getting data out of the appsink is done by setting callbacks on it.
+    // The appsink will then call those handlers, as soon as data is available.
+    appsink.set_callbacks(
+        AppSinkCallbacks::builder()
+            // Add a handler to the "new-sample" signal.
+            .new_sample(move |appsink| {
+                // Pull the sample in question out of the appsink's buffer.
+                let sample = appsink.pull_sample().map_err(|_| FlowError::Eos)?;
+
+                // the sources push an EOS on, which ends the main loop
+                if !deliver(convert(appsink, sample)?) {
+                    return Err(FlowError::Eos);
+                }
+
+                Ok(gstreamer::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+}
+
+fn buffer(appsink: &AppSink, sample: &Sample) -> std::result::Result<Buffer, FlowError> {
+    sample.buffer_owned().ok_or_else(|| {
+        element_error!(
+            appsink,
+            gstreamer::ResourceError::Failed,
+            ("Failed to get buffer from appsink")
+        );
+
+        FlowError::Error
+    })
+}
+
+// A copy of the data of a sample
+pub(crate) fn copy_bytes(
+    appsink: &AppSink,
+    sample: Sample,
+) -> std::result::Result<BytesMut, FlowError> {
+    let buffer = buffer(appsink, &sample)?;
+
+    // At this point, buffer is only a reference to an existing memory region somewhere.
+    // When we want to access its content, we have to map it while requesting the required
+    // mode of access (read, read/write).
+    // This type of abstraction is necessary, because the buffer in question might not be
+    // on the machine's main memory itself, but rather in the GPU's memory.
+    // So mapping the buffer makes the underlying memory region accessible to us.
+    // See: https://gstreamer.freedesktop.org/documentation/plugin-development/advanced/allocation.html
+    let map = buffer.map_readable().map_err(|_| {
+        element_error!(
+            appsink,
+            gstreamer::ResourceError::Failed,
+            ("Failed to map buffer readable")
+        );
+
+        FlowError::Error
+    })?;
+
+    // We know what format the data in the memory region has, since we requested
+    // it by setting the appsink's caps. So what we do here is interpret the
+    // memory region we mapped as an array of u8 packets.
+    let samples = map.as_slice_of::<u8>().map_err(|_| {
+        element_error!(
+            appsink,
+            gstreamer::ResourceError::Failed,
+            ("Failed to interprete buffer as S16 PCM")
+        );
+
+        FlowError::Error
+    })?;
+
+    Ok(BytesMut::from(samples))
+}
+
+// The data of a sample without a copy, the buffer stays mapped as long as the
+// bytes (or slices of them) are around
+pub(crate) fn share_bytes(
+    appsink: &AppSink,
+    sample: Sample,
+) -> std::result::Result<Bytes, FlowError> {
+    let map = buffer(appsink, &sample)?
+        .into_mapped_buffer_readable()
+        .map_err(|_| {
+            element_error!(
+                appsink,
+                gstreamer::ResourceError::Failed,
+                ("Failed to map buffer readable")
+            );
+
+            FlowError::Error
+        })?;
+
+    Ok(Bytes::from_owner(map))
+}
+
+// The sample itself, with its buffer and caps
+pub(crate) fn keep_sample(_: &AppSink, sample: Sample) -> std::result::Result<Sample, FlowError> {
+    Ok(sample)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn send_all(options: AppsinkOptions) -> (Vec<u8>, u64) {
+        let (sender, receiver) = options.channel::<BytesMut>();
+
+        for byte in 0..5 {
+            assert!(sender.send(BytesMut::from(&[byte][..])));
+        }
+
+        let received = receiver.try_iter().map(|bytes| bytes[0]).collect();
+
+        (received, sender.dropped().count())
+    }
+
+    #[test]
+    fn it_drops_buffers_by_policy() {
+        let options = AppsinkOptions::new().capacity(2);
+
+        assert_eq!(
+            send_all(options.clone().policy(DropPolicy::DropNewest)),
+            (vec![0, 1], 3)
+        );
+        assert_eq!(
+            send_all(options.policy(DropPolicy::DropOldest)),
+            (vec![3, 4], 3)
+        );
+        assert_eq!(
+            send_all(AppsinkOptions::new().unbounded()),
+            (vec![0, 1, 2, 3, 4], 0)
+        );
+        assert_eq!(
+            send_all(AppsinkOptions::new().capacity(0)),
+            (vec![0, 1, 2, 3, 4], 0)
+        );
+    }
+
+    #[test]
+    fn it_notices_a_consumer_that_is_gone() {
+        for policy in [
+            DropPolicy::Block,
+            DropPolicy::DropOldest,
+            DropPolicy::DropNewest,
+        ] {
+            let (sender, receiver) = AppsinkOptions::new()
+                .capacity(1)
+                .policy(policy)
+                .channel::<BytesMut>();
+            let clone = receiver.clone();
+
+            drop(receiver);
+            assert!(sender.send(BytesMut::new()), "{:?}", policy);
+
+            drop(clone);
+            assert!(!sender.send(BytesMut::new()), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn it_configures_the_appsink() {
+        let appsink = AppsinkOptions::new()
+            .max_buffers(5)
+            .drop(true)
+            .appsink("sink");
+
+        assert_eq!(
+            appsink.to_string(),
+            "appsink name=sink max-buffers=5 drop=true"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use crate::appsink::{copy_bytes, AppsinkOptions, BufferReceiver, DroppedBuffers};
+use crate::error::Result;
+use crate::main_loop::{main_loop_simple, Command};
+use crate::record::RecordOptions;
+use crate::recording_handle::RecordingHandle;
+use crate::{appsink_pipeline, rtp_stream_record, rtp_udp_client_record};
+use bytes::BytesMut;
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use futures_core::Stream;
+use gstreamer::prelude::*;
+use gstreamer::Pipeline;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+// The task to wake when a thread feeding a stream or future made progress
+struct Wakeup<T> {
+    waker: Option<Waker>,
+
+    // set once the feeding thread is done, with what it has to hand over
+    finished: Option<T>,
+}
+
+impl<T> Default for Wakeup<T> {
+    fn default() -> Self {
+        Wakeup {
+            waker: None,
+            finished: None,
+        }
+    }
+}
+
+type Shared<T> = Arc<Mutex<Wakeup<T>>>;
+
+fn wake<T>(shared: &Shared<T>, finished: Option<T>) {
+    let mut shared = shared.lock().unwrap();
+
+    if finished.is_some() {
+        shared.finished = finished;
+    }
+
+    if let Some(waker) = shared.waker.take() {
+        waker.wake();
+    }
+}
+
+// The data of an appsink pipeline's buffers, ending with the pipeline.
+// Dropping the stream stops the pipeline.
+pub struct AppsinkStream {
+    receiver: BufferReceiver<BytesMut>,
+    dropped: DroppedBuffers,
+    shared: Shared<()>,
+    pipeline: Pipeline,
+}
+
+impl AppsinkStream {
+    // Buffers the drop policy dropped so far
+    pub fn dropped(&self) -> &DroppedBuffers {
+        &self.dropped
+    }
+}
+
+impl Stream for AppsinkStream {
+    type Item = BytesMut;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<BytesMut>> {
+        // register before looking at the channel so no buffer sent in between is missed
+        let mut shared = self.shared.lock().unwrap();
+
+        shared.waker = Some(cx.waker().clone());
+
+        match self.receiver.try_recv() {
+            Ok(bytes) => Poll::Ready(Some(bytes)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) if shared.finished.is_some() => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for AppsinkStream {
+    // ends the bus loop of the pipeline, which then shuts the pipeline down
+    fn drop(&mut self) {
+        if let Some(bus) = self.pipeline.bus() {
+            if bus.post(&gstreamer::message::Eos::new()).is_err() {
+                log::error!("Could not stop the pipeline of a dropped stream");
+            }
+        }
+    }
+}
+
+// Like `crate::create_and_start_appsink_pipeline`, with the buffers as a stream
+pub fn create_and_start_appsink_pipeline(launch: &str) -> Result<AppsinkStream> {
+    create_and_start_appsink_pipeline_with_options(launch, &AppsinkOptions::default())
+}
+
+// Like `crate::create_and_start_appsink_pipeline_with_options`, with the buffers
+// as a stream that counts dropped buffers
+pub fn create_and_start_appsink_pipeline_with_options(
+    launch: &str,
+    options: &AppsinkOptions,
+) -> Result<AppsinkStream> {
+    let (sender, rx) = options.channel();
+    let dropped = sender.dropped();
+    let shared = Shared::default();
+    let buffer_shared = shared.clone();
+
+    let pipeline = appsink_pipeline(launch, options, copy_bytes, move |bytes| {
+        let sent = sender.send(bytes);
+
+        // not an error, just the stream is no longer around
+        if !sent {
+            log::info!("Stream not able to receive bytes from the pipeline");
+        }
+
+        wake(&buffer_shared, None);
+        sent
+    })?;
+    let stream = AppsinkStream {
+        receiver: rx,
+        dropped,
+        shared: shared.clone(),
+        pipeline: pipeline.clone(),
+    };
+
+    std::thread::spawn(move || {
+        if let Err(err) = main_loop_simple(pipeline) {
+            log::error!("pipeline error: {}", err);
+        }
+
+        wake(&shared, Some(()));
+    });
+
+    Ok(stream)
+}
+
+// A running recording, resolving to the path of the file once it is finalized.
+// Dropping it stops the recording.
+pub struct Recording {
+    commands: Sender<Command>,
+    shared: Shared<Result<PathBuf>>,
+}
+
+impl Recording {
+    // Wake the future once the recording of the handle has ended
+    fn spawn(handle: RecordingHandle) -> Self {
+        let shared = Shared::default();
+        let recording = Recording {
+            commands: handle.commands(),
+            shared: shared.clone(),
+        };
+
+        std::thread::spawn(move || wake(&shared, Some(handle.wait())));
+
+        recording
+    }
+
+    // Finish the file, the recording resolves once it is written
+    pub fn stop(&self) {
+        if self.commands.send(Command::Stop).is_err() {
+            log::info!("Recording has already ended");
+        }
+    }
+}
+
+impl Future for Recording {
+    type Output = Result<PathBuf>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<PathBuf>> {
+        let mut shared = self.shared.lock().unwrap();
+
+        match shared.finished.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Recording {
+    // the file is still finalized in the background
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+    }
+}
+
+// Record RTP packets received through a channel, see `rtp_stream_record`
+pub fn record(
+    filename: &str,
+    receiver: Receiver<BytesMut>,
+    options: RecordOptions,
+) -> Result<Recording> {
+    rtp_stream_record::start(filename, receiver, options).map(Recording::spawn)
+}
+
+// Record RTP packets received on a UDP port, see `rtp_udp_client_record`
+pub fn record_udp(port: &str, filename: &str, options: RecordOptions) -> Result<Recording> {
+    rtp_udp_client_record::start(port, filename, options).map(Recording::spawn)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::rtp_stream::start;
+    use std::path::Path;
+    use std::task::Wake;
+    use std::thread::{sleep, Thread};
+    use std::time::Duration;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // A minimal executor, polling on the current thread until ready
+    fn block_on<T>(mut poll: impl FnMut(&mut Context<'_>) -> Poll<T>) -> T {
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match poll(&mut cx) {
+                Poll::Ready(value) => return value,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn it_streams_buffers() {
+        let launch = "videotestsrc num-buffers=5 ! video/x-raw,width=64,height=48";
+        let mut stream = create_and_start_appsink_pipeline(launch).unwrap();
+        let mut count = 0;
+
+        while block_on(|cx| Pin::new(&mut stream).poll_next(cx)).is_some() {
+            count += 1;
+        }
+
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn it_records_asynchronously() {
+        env_logger::try_init().ok();
+
+        let filename = "test/output/it_records_asynchronously.mp4";
+        let (_tx, rx) = start();
+        let mut recording = record(filename, rx, RecordOptions::default()).unwrap();
+
+        sleep(Duration::from_millis(2000));
+        recording.stop();
+
+        let path = block_on(|cx| Pin::new(&mut recording).poll(cx)).unwrap();
+
+        assert_eq!(path, Path::new(filename));
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,234 @@
+use crate::error::{MediaPipelineError, Result};
+use crate::pipeline_builder::{Chain, ElementSpec};
+use std::collections::HashMap;
+
+// The media specific parts of receiving an RTP encoding
+pub trait RtpCodec {
+    // RTP encoding name as used in SDP and caps
+    fn encoding_name(&self) -> &'static str;
+
+    fn clock_rate(&self) -> u32;
+
+    // The payload type used unless a PayloadTypeMap says otherwise
+    fn default_payload_type(&self) -> u8;
+
+    // Depayloader and (if needed) parser turning RTP into muxable frames
+    fn depayload_chain(&self) -> Chain;
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+
+    // Needs GStreamer 1.20 or later for av1parse and the rtp plugin of
+    // gst-plugins-rs for the RTP (de)payloader, which the usual plugin
+    // packages do not include
+    Av1,
+}
+
+impl VideoCodec {
+    // Where the codec's elements come from when the usual plugin packages do
+    // not include them
+    fn plugin_requirement(&self) -> Option<&'static str> {
+        match self {
+            VideoCodec::Av1 => Some(
+                "GStreamer 1.20 or later with the AV1 elements of gst-plugins-bad \
+                and the rtp plugin of gst-plugins-rs",
+            ),
+            _ => None,
+        }
+    }
+
+    // Fail before building a pipeline when the codec needs plugins that are
+    // usually missing, naming them
+    pub(crate) fn check_installed(&self, chain: &Chain) -> Result<()> {
+        let requirement = match self.plugin_requirement() {
+            Some(requirement) => requirement,
+            None => return Ok(()),
+        };
+
+        gstreamer::init()?;
+
+        match chain
+            .factories()
+            .into_iter()
+            .find(|factory| gstreamer::ElementFactory::find(factory).is_none())
+        {
+            Some(factory) => Err(MediaPipelineError::MissingElementError(format!(
+                "{} ({} needs {})",
+                factory,
+                self.encoding_name(),
+                requirement
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn from_encoding_name(encoding_name: &str) -> Result<Self> {
+        match encoding_name.to_uppercase().as_str() {
+            "H264" => Ok(VideoCodec::H264),
+            "H265" => Ok(VideoCodec::H265),
+            "VP8" => Ok(VideoCodec::Vp8),
+            "VP9" => Ok(VideoCodec::Vp9),
+            "AV1" => Ok(VideoCodec::Av1),
+            _ => Err(MediaPipelineError::UnsupportedCodecError(
+                encoding_name.to_owned(),
+            )),
+        }
+    }
+}
+
+impl RtpCodec for VideoCodec {
+    fn encoding_name(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "H264",
+            VideoCodec::H265 => "H265",
+            VideoCodec::Vp8 => "VP8",
+            VideoCodec::Vp9 => "VP9",
+            VideoCodec::Av1 => "AV1",
+        }
+    }
+
+    fn clock_rate(&self) -> u32 {
+        90000
+    }
+
+    fn default_payload_type(&self) -> u8 {
+        96
+    }
+
+    fn depayload_chain(&self) -> Chain {
+        match self {
+            VideoCodec::H264 => Chain::new()
+                .element(ElementSpec::new("rtph264depay"))
+                .element(ElementSpec::new("h264parse").property("config-interval", -1)),
+            VideoCodec::H265 => Chain::new()
+                .element(ElementSpec::new("rtph265depay"))
+                .element(ElementSpec::new("h265parse").property("config-interval", -1)),
+            VideoCodec::Vp8 => Chain::new().element(ElementSpec::new("rtpvp8depay")),
+            VideoCodec::Vp9 => Chain::new().element(ElementSpec::new("rtpvp9depay")),
+            VideoCodec::Av1 => Chain::new()
+                .element(ElementSpec::new("rtpav1depay"))
+                .element(ElementSpec::new("av1parse")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AudioCodec {
+    Opus,
+    Pcmu,
+    Pcma,
+}
+
+impl AudioCodec {
+    pub fn from_encoding_name(encoding_name: &str) -> Result<Self> {
+        match encoding_name.to_uppercase().as_str() {
+            "OPUS" => Ok(AudioCodec::Opus),
+            "PCMU" => Ok(AudioCodec::Pcmu),
+            "PCMA" => Ok(AudioCodec::Pcma),
+            _ => Err(MediaPipelineError::UnsupportedCodecError(
+                encoding_name.to_owned(),
+            )),
+        }
+    }
+}
+
+impl RtpCodec for AudioCodec {
+    fn encoding_name(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "OPUS",
+            AudioCodec::Pcmu => "PCMU",
+            AudioCodec::Pcma => "PCMA",
+        }
+    }
+
+    fn clock_rate(&self) -> u32 {
+        match self {
+            AudioCodec::Opus => 48000,
+            AudioCodec::Pcmu | AudioCodec::Pcma => 8000,
+        }
+    }
+
+    // PCMU and PCMA have static payload types (RFC 3551), Opus is commonly 111
+    fn default_payload_type(&self) -> u8 {
+        match self {
+            AudioCodec::Opus => 111,
+            AudioCodec::Pcmu => 0,
+            AudioCodec::Pcma => 8,
+        }
+    }
+
+    fn depayload_chain(&self) -> Chain {
+        match self {
+            AudioCodec::Opus => Chain::new()
+                .element(ElementSpec::new("rtpopusdepay"))
+                .element(ElementSpec::new("opusparse")),
+            AudioCodec::Pcmu => Chain::new().element(ElementSpec::new("rtppcmudepay")),
+            AudioCodec::Pcma => Chain::new().element(ElementSpec::new("rtppcmadepay")),
+        }
+    }
+}
+
+// Payload types negotiated for each encoding, falling back to the codec's default
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PayloadTypeMap {
+    payload_types: HashMap<&'static str, u8>,
+}
+
+impl PayloadTypeMap {
+    pub fn new() -> Self {
+        PayloadTypeMap::default()
+    }
+
+    pub fn set(mut self, codec: impl RtpCodec, payload_type: u8) -> Self {
+        self.payload_types
+            .insert(codec.encoding_name(), payload_type);
+        self
+    }
+
+    pub fn get(&self, codec: &impl RtpCodec) -> u8 {
+        self.payload_types
+            .get(codec.encoding_name())
+            .copied()
+            .unwrap_or_else(|| codec.default_payload_type())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_maps_payload_types() {
+        let payload_types = PayloadTypeMap::new()
+            .set(VideoCodec::Vp8, 100)
+            .set(VideoCodec::Vp9, 101);
+
+        assert_eq!(payload_types.get(&VideoCodec::Vp8), 100);
+        assert_eq!(payload_types.get(&VideoCodec::Vp9), 101);
+        assert_eq!(payload_types.get(&VideoCodec::H264), 96);
+        assert_eq!(payload_types.get(&AudioCodec::Pcma), 8);
+    }
+
+    #[test]
+    fn it_parses_encoding_names() {
+        assert_eq!(
+            VideoCodec::from_encoding_name("vp8").unwrap(),
+            VideoCodec::Vp8
+        );
+        assert_eq!(
+            VideoCodec::from_encoding_name("H265").unwrap(),
+            VideoCodec::H265
+        );
+        assert!(VideoCodec::from_encoding_name("MP4V-ES").is_err());
+        assert_eq!(
+            AudioCodec::from_encoding_name("opus").unwrap(),
+            AudioCodec::Opus
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use crate::codec::{AudioCodec, VideoCodec};
+use crate::pipeline_builder::ElementSpec;
+
+// Length of a fragment in fragmented MP4 recordings
+const FRAGMENT_DURATION_MS: u32 = 1000;
+
+// File formats the recorders can write
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Container {
+    Mp4,
+
+    // MP4 written as a series of fragments, readable while it is written
+    // and playable up to the last complete fragment if the process dies
+    FragmentedMp4,
+    Matroska,
+    WebM,
+    MpegTs,
+}
+
+impl Container {
+    // The container used when none is chosen: MP4 where possible, Matroska otherwise
+    pub fn default_for(video: VideoCodec, audio: Option<AudioCodec>) -> Self {
+        let mp4 = Container::Mp4;
+
+        let mp4_audio = audio.into_iter().all(|audio| mp4.supports_audio(audio));
+
+        if mp4.supports_video(video) && mp4_audio {
+            mp4
+        } else {
+            Container::Matroska
+        }
+    }
+
+    pub fn supports_video(&self, codec: VideoCodec) -> bool {
+        match self {
+            Container::Mp4 | Container::FragmentedMp4 => {
+                matches!(codec, VideoCodec::H264 | VideoCodec::H265 | VideoCodec::Av1)
+            }
+            Container::Matroska => true,
+            Container::WebM => matches!(codec, VideoCodec::Vp8 | VideoCodec::Vp9 | VideoCodec::Av1),
+            Container::MpegTs => matches!(codec, VideoCodec::H264 | VideoCodec::H265),
+        }
+    }
+
+    pub fn supports_audio(&self, codec: AudioCodec) -> bool {
+        match self {
+            Container::Matroska => true,
+            _ => codec == AudioCodec::Opus,
+        }
+    }
+
+    // The usual file extension, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Mp4 | Container::FragmentedMp4 => "mp4",
+            Container::Matroska => "mkv",
+            Container::WebM => "webm",
+            Container::MpegTs => "ts",
+        }
+    }
+
+    pub(crate) fn muxer(&self) -> ElementSpec {
+        match self {
+            Container::Mp4 => ElementSpec::new("mp4mux"),
+            Container::FragmentedMp4 => {
+                ElementSpec::new("mp4mux").property("fragment-duration", FRAGMENT_DURATION_MS)
+            }
+            Container::Matroska => ElementSpec::new("matroskamux"),
+            Container::WebM => ElementSpec::new("webmmux"),
+            Container::MpegTs => ElementSpec::new("mpegtsmux"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_picks_a_default_container() {
+        assert_eq!(
+            Container::default_for(VideoCodec::H264, None),
+            Container::Mp4
+        );
+        assert_eq!(
+            Container::default_for(VideoCodec::H264, Some(AudioCodec::Pcmu)),
+            Container::Matroska
+        );
+        assert_eq!(
+            Container::default_for(VideoCodec::Vp8, None),
+            Container::Matroska
+        );
+    }
+
+    #[test]
+    fn it_checks_codec_support() {
+        assert!(Container::MpegTs.supports_video(VideoCodec::H265));
+        assert!(!Container::MpegTs.supports_video(VideoCodec::Vp9));
+        assert!(Container::WebM.supports_video(VideoCodec::Vp9));
+        assert!(!Container::WebM.supports_video(VideoCodec::H264));
+        assert!(!Container::WebM.supports_audio(AudioCodec::Pcma));
+        assert!(Container::Matroska.supports_audio(AudioCodec::Pcma));
+    }
+}
@@ -6,6 +6,9 @@ pub type Result<T> = std::result::Result<T, MediaPipelineError>;
 
 #[derive(Error, Debug)]
 pub enum MediaPipelineError {
+    #[error("Error from the pipeline: {0}")]
+    BusError(String),
+
     #[error("Could not create element: {0}")]
     CreateElementError(&'static str),
 
@@ -21,11 +24,41 @@ pub enum MediaPipelineError {
     #[error("Failed to initialize GStreamer: {0}")]
     InitError(String),
 
+    #[error("I/O error: {0}")]
+    IoError(String),
+
+    #[error("Failed to link: {0}")]
+    LinkError(String),
+
+    #[error("Element is not installed: {0}")]
+    MissingElementError(String),
+
     #[error("Failed to parse the launch: {0}")]
     ParseLaunchError(String),
 
+    #[error("Invalid pipeline: {0}")]
+    PipelineBuilderError(String),
+
+    #[error("Invalid record options: {0}")]
+    RecordOptionsError(String),
+
+    #[error("Invalid RTP packet: {0}")]
+    RtpError(String),
+
+    #[error("Invalid SDP: {0}")]
+    SdpError(String),
+
     #[error("Failed to parse the launch: {0}")]
     StateChangeError(String),
+
+    #[error("Failed to stop: {0}")]
+    StopError(String),
+
+    #[error("Invalid test source: {0}")]
+    TestSourceError(String),
+
+    #[error("Unsupported codec: {0}")]
+    UnsupportedCodecError(String),
 }
 
 impl From<GlibError> for MediaPipelineError {
@@ -34,8 +67,20 @@ impl From<GlibError> for MediaPipelineError {
     }
 }
 
+impl From<glib::BoolError> for MediaPipelineError {
+    fn from(error: glib::BoolError) -> Self {
+        MediaPipelineError::GlibError(error.to_string())
+    }
+}
+
 impl From<StateChangeError> for MediaPipelineError {
     fn from(error: StateChangeError) -> Self {
         MediaPipelineError::StateChangeError(error.to_string())
     }
 }
+
+impl From<std::io::Error> for MediaPipelineError {
+    fn from(error: std::io::Error) -> Self {
+        MediaPipelineError::IoError(error.to_string())
+    }
+}
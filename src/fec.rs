@@ -0,0 +1,300 @@
+use crate::error::{MediaPipelineError, Result};
+use crate::jitter_buffer::{JitterBufferOptions, AUDIO_JITTERBUFFER, VIDEO_JITTERBUFFER};
+use crate::pipeline_builder::{Chain, ElementSpec};
+use crate::record::RecordOptions;
+use crate::rtcp::{AUDIO_SESSION, RTPBIN, VIDEO_SESSION};
+use gstreamer::prelude::*;
+use gstreamer::{Element, Pipeline};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Names of the elements storing packets and recovering lost ones
+const VIDEO_FEC_STORAGE: &str = "video_fec_storage";
+const AUDIO_FEC_STORAGE: &str = "audio_fec_storage";
+const VIDEO_FEC_DECODER: &str = "video_fec_decoder";
+const AUDIO_FEC_DECODER: &str = "audio_fec_decoder";
+
+// Packets are kept a little longer than the jitter buffer holds them, so the
+// ones a late FEC packet protects are still there
+const STORAGE_MARGIN: Duration = Duration::from_millis(50);
+
+// The names of the elements of a stream's media, "video" or "audio"
+fn names(media: &str) -> (&'static str, &'static str, &'static str) {
+    match media {
+        "audio" => (AUDIO_JITTERBUFFER, AUDIO_FEC_STORAGE, AUDIO_FEC_DECODER),
+        _ => (VIDEO_JITTERBUFFER, VIDEO_FEC_STORAGE, VIDEO_FEC_DECODER),
+    }
+}
+
+// Forward error correction protecting a received stream.  Browsers wrap the
+// media and the ULPFEC (RFC 5109) packets in RED (RFC 2198) packets, other
+// senders send ULPFEC packets with a payload type of their own.  Both have to
+// share the SSRC and sequence numbers of the media.  FlexFEC (RFC 8627) is
+// not supported, GStreamer has no decoder for it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FecOptions {
+    red_payload_type: Option<u8>,
+    ulpfec_payload_type: Option<u8>,
+}
+
+impl FecOptions {
+    pub fn new() -> Self {
+        FecOptions::default()
+    }
+
+    // Unwrap the RED packets of a payload type
+    pub fn red(mut self, payload_type: u8) -> Self {
+        self.red_payload_type = Some(payload_type);
+        self
+    }
+
+    // Recover lost packets from the ULPFEC packets of a payload type
+    pub fn ulpfec(mut self, payload_type: u8) -> Self {
+        self.ulpfec_payload_type = Some(payload_type);
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(fec_error(message));
+
+        match (self.red_payload_type, self.ulpfec_payload_type) {
+            (None, None) => invalid("needs a RED or ULPFEC payload type"),
+            (Some(red), Some(ulpfec)) if red == ulpfec => {
+                invalid("RED and ULPFEC need different payload types")
+            }
+            (red, ulpfec) if red.into_iter().chain(ulpfec).any(|pt| pt > 127) => {
+                invalid("a payload type is above 127")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Whether lost packets are recovered, which needs the jitter buffer to
+    // report them lost
+    pub(crate) fn recovers(&self) -> bool {
+        self.ulpfec_payload_type.is_some()
+    }
+
+    // Unwrap RED packets, before the stream reaches its jitter buffer
+    pub(crate) fn unwrap_red(&self, chain: Chain) -> Chain {
+        match self.red_payload_type {
+            Some(payload_type) => {
+                chain.element(ElementSpec::new("rtpreddec").property("pt", payload_type))
+            }
+            None => chain,
+        }
+    }
+}
+
+fn fec_error(message: &str) -> MediaPipelineError {
+    MediaPipelineError::RecordOptionsError(format!("FEC {}", message))
+}
+
+// A stream received through a jitter buffer of its own.  With FEC the packets
+// are stored before the jitter buffer and lost ones recovered after it, the
+// storage is handed to the decoder by `attach_fec` once the pipeline is built.
+pub(crate) fn jitter_buffer_chain(
+    media: &str,
+    source: Chain,
+    jitter_buffer: &JitterBufferOptions,
+    fec: Option<&FecOptions>,
+) -> Result<Chain> {
+    let (jitter_buffer_name, storage, decoder) = names(media);
+    let element = jitter_buffer.element(jitter_buffer_name);
+    let fec = match fec {
+        Some(fec) => fec,
+        None => return Ok(source.element(element)),
+    };
+
+    fec.validate()?;
+
+    let source = fec.unwrap_red(source);
+
+    Ok(match fec.ulpfec_payload_type {
+        Some(payload_type) => source
+            .element(
+                ElementSpec::new("rtpstorage")
+                    .name(storage)
+                    .property("size-time", storage_time(jitter_buffer).as_nanos() as u64),
+            )
+            .element(element.property("do-lost", true))
+            .element(
+                ElementSpec::new("rtpulpfecdec")
+                    .name(decoder)
+                    .property("pt", payload_type),
+            ),
+        None => source.element(element),
+    })
+}
+
+fn storage_time(jitter_buffer: &JitterBufferOptions) -> Duration {
+    jitter_buffer.latency_duration() + STORAGE_MARGIN
+}
+
+// Hand the packets stored for each protected stream the pipeline receives to
+// its FEC decoder.  In an rtpbin the sessions store the packets, and the
+// decoders are requested by the rtpbin when a stream arrives.  Has to be called
+// after `attach_retransmission`, which links the sessions of streams with RTX.
+pub(crate) fn attach_fec(pipeline: &Pipeline, options: &RecordOptions) -> Result<()> {
+    let streams = [
+        ("video", VIDEO_SESSION, options.fec_options()),
+        ("audio", AUDIO_SESSION, options.audio_fec_options()),
+    ];
+    let decoders = streams
+        .iter()
+        .copied()
+        .filter_map(|(media, session, fec)| Some((media, session, fec?.ulpfec_payload_type?)))
+        .collect::<Vec<_>>();
+
+    if decoders.is_empty() {
+        return Ok(());
+    }
+
+    let rtpbin = match pipeline.by_name(RTPBIN) {
+        Some(rtpbin) => rtpbin,
+        None => {
+            for (media, _, _) in decoders {
+                let (_, storage, decoder) = names(media);
+
+                if let (Some(storage), Some(decoder)) =
+                    (pipeline.by_name(storage), pipeline.by_name(decoder))
+                {
+                    decoder.set_property("storage", &storage.property("internal-storage")?)?;
+                }
+            }
+
+            return Ok(());
+        }
+    };
+
+    let storage_time = storage_time(options.jitter_buffer_options()).as_nanos() as u64;
+    let mut payload_types = HashMap::new();
+
+    // streams without a session in the rtpbin (no audio port) are skipped
+    for (media, session, payload_type) in decoders {
+        let storage = match rtpbin
+            .emit_by_name("get-storage", &[&session])?
+            .and_then(|storage| storage.get::<Element>().ok())
+        {
+            Some(storage) => storage,
+            None => continue,
+        };
+
+        storage.set_property("size-time", storage_time)?;
+        payload_types.insert(session, (names(media).2, payload_type));
+    }
+
+    rtpbin.connect("request-fec-decoder", false, move |values| {
+        let decoder = values
+            .get(1)
+            .and_then(|session| session.get::<u32>().ok())
+            .and_then(|session| {
+                let (name, payload_type) = payload_types.get(&session)?;
+                let rtpbin = values[0].get::<Element>().ok()?;
+
+                fec_decoder(&rtpbin, session, name, *payload_type)
+                    .map_err(|error| {
+                        log::error!("No FEC decoder for session {}: {}", session, error)
+                    })
+                    .ok()
+            });
+
+        Some(decoder.to_value())
+    })?;
+
+    Ok(())
+}
+
+// An ULPFEC decoder recovering from the packets stored by an rtpbin session
+fn fec_decoder(rtpbin: &Element, session: u32, name: &str, payload_type: u8) -> Result<Element> {
+    let decoder = gstreamer::ElementFactory::make("rtpulpfecdec", Some(name))
+        .map_err(|_| MediaPipelineError::MissingElementError("rtpulpfecdec".to_owned()))?;
+    let storage = rtpbin
+        .emit_by_name("get-internal-storage", &[&session])?
+        .ok_or(MediaPipelineError::CreateElementError("rtpstorage"))?;
+
+    decoder.set_property("pt", u32::from(payload_type))?;
+    decoder.set_property("storage", &storage)?;
+
+    Ok(decoder)
+}
+
+// Packets of a stream protected by ULPFEC since the recording started
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FecStats {
+    // "video" or "audio"
+    pub media: String,
+
+    // lost packets restored from FEC packets
+    pub recovered: u32,
+
+    // lost packets the FEC packets received were not enough for
+    pub unrecovered: u32,
+}
+
+// The stats of the FEC decoders in a pipeline, empty if it has none
+pub(crate) fn fec_stats(pipeline: &Pipeline) -> Vec<FecStats> {
+    [("video", VIDEO_FEC_DECODER), ("audio", AUDIO_FEC_DECODER)]
+        .iter()
+        .filter_map(|(media, name)| {
+            let decoder = pipeline.by_name(name)?;
+            let count = |property: &str| {
+                decoder
+                    .property(property)
+                    .ok()
+                    .and_then(|count| count.get::<u32>().ok())
+                    .unwrap_or(0)
+            };
+
+            Some(FecStats {
+                media: (*media).to_owned(),
+                recovered: count("recovered"),
+                unrecovered: count("unrecovered"),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_validates_fec_options() {
+        assert!(FecOptions::new().validate().is_err());
+        assert!(FecOptions::new().red(122).ulpfec(122).validate().is_err());
+        assert!(FecOptions::new().ulpfec(128).validate().is_err());
+        assert!(FecOptions::new().red(122).validate().is_ok());
+        assert!(FecOptions::new().red(122).ulpfec(123).validate().is_ok());
+    }
+
+    #[test]
+    fn it_recovers_packets_after_the_jitter_buffer() {
+        let source = || Chain::new().element(ElementSpec::new("udpsrc").property("port", 5000));
+        let jitter_buffer = JitterBufferOptions::new();
+        let fec = FecOptions::new().red(122).ulpfec(123);
+        assert_eq!(
+            jitter_buffer_chain("video", source(), &jitter_buffer, Some(&fec))
+                .unwrap()
+                .to_string(),
+            "udpsrc port=5000 ! rtpreddec pt=122 ! \
+            rtpstorage name=video_fec_storage size-time=250000000 ! \
+            rtpjitterbuffer name=video_jitterbuffer latency=200 drop-on-latency=false mode=slave \
+            do-lost=true ! rtpulpfecdec name=video_fec_decoder pt=123"
+        );
+        assert_eq!(
+            jitter_buffer_chain("audio", source(), &jitter_buffer, Some(&fec.red(100)))
+                .unwrap()
+                .to_string(),
+            "udpsrc port=5000 ! rtpreddec pt=100 ! \
+            rtpstorage name=audio_fec_storage size-time=250000000 ! \
+            rtpjitterbuffer name=audio_jitterbuffer latency=200 drop-on-latency=false mode=slave \
+            do-lost=true ! rtpulpfecdec name=audio_fec_decoder pt=123"
+        );
+        assert!(
+            jitter_buffer_chain("video", source(), &jitter_buffer, Some(&FecOptions::new()))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use crate::pipeline_builder::{ElementSpec, PropertyValue};
+use crate::rtcp::RTPBIN;
+use gstreamer::prelude::*;
+use gstreamer::Pipeline;
+use std::time::Duration;
+
+// Names of the jitter buffers of the received streams
+pub(crate) const VIDEO_JITTERBUFFER: &str = "video_jitterbuffer";
+pub(crate) const AUDIO_JITTERBUFFER: &str = "audio_jitterbuffer";
+
+// How the jitter buffer relates the timestamps of packets to the pipeline clock
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JitterBufferMode {
+    // only use the RTP timestamps
+    None,
+
+    // follow the clock of the sender
+    Slave,
+
+    // buffer up to the latency before starting, for non-live senders
+    Buffer,
+
+    // the sender and receiver clocks are synchronized
+    Synced,
+}
+
+impl JitterBufferMode {
+    fn nick(&self) -> &'static str {
+        match self {
+            JitterBufferMode::None => "none",
+            JitterBufferMode::Slave => "slave",
+            JitterBufferMode::Buffer => "buffer",
+            JitterBufferMode::Synced => "synced",
+        }
+    }
+}
+
+// Reordering of packets received from the network.  Packets arriving later
+// than the latency are considered lost.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JitterBufferOptions {
+    latency: Duration,
+    drop_on_latency: bool,
+    mode: JitterBufferMode,
+}
+
+impl Default for JitterBufferOptions {
+    fn default() -> Self {
+        JitterBufferOptions {
+            latency: Duration::from_millis(200),
+            drop_on_latency: false,
+            mode: JitterBufferMode::Slave,
+        }
+    }
+}
+
+impl JitterBufferOptions {
+    pub fn new() -> Self {
+        JitterBufferOptions::default()
+    }
+
+    // How long packets are held to wait for missing ones, in whole milliseconds
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    // Drop the oldest packets instead of growing beyond the latency
+    pub fn drop_on_latency(mut self, drop_on_latency: bool) -> Self {
+        self.drop_on_latency = drop_on_latency;
+        self
+    }
+
+    pub fn mode(mut self, mode: JitterBufferMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub(crate) fn latency_duration(&self) -> Duration {
+        self.latency
+    }
+
+    pub(crate) fn element(&self, name: &str) -> ElementSpec {
+        ElementSpec::new("rtpjitterbuffer")
+            .name(name)
+            .property("latency", self.latency.as_millis() as u64)
+            .property("drop-on-latency", self.drop_on_latency)
+            .property("mode", PropertyValue::Nick(self.mode.nick().into()))
+    }
+
+    // An rtpbin whose jitter buffers are set up the same way
+    pub(crate) fn rtpbin(&self) -> ElementSpec {
+        ElementSpec::new("rtpbin")
+            .name(RTPBIN)
+            .property("latency", self.latency.as_millis() as u64)
+            .property("drop-on-latency", self.drop_on_latency)
+            .property("buffer-mode", PropertyValue::Nick(self.mode.nick().into()))
+    }
+}
+
+// Packet counts of the jitter buffer of a stream since the recording started
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct JitterBufferStats {
+    // "video" or "audio"
+    pub media: String,
+    pub pushed: u64,
+    pub lost: u64,
+    pub late: u64,
+    pub duplicates: u64,
+}
+
+impl JitterBufferStats {
+    fn from_structure(media: &str, structure: &gstreamer::StructureRef) -> Self {
+        let count = |field: &str| structure.get::<u64>(field).unwrap_or(0);
+
+        JitterBufferStats {
+            media: media.to_owned(),
+            pushed: count("num-pushed"),
+            lost: count("num-lost"),
+            late: count("num-late"),
+            duplicates: count("num-duplicates"),
+        }
+    }
+}
+
+// The stats of the jitter buffers in a pipeline, empty if it has none
+pub(crate) fn jitter_buffer_stats(pipeline: &Pipeline) -> Vec<JitterBufferStats> {
+    [("video", VIDEO_JITTERBUFFER), ("audio", AUDIO_JITTERBUFFER)]
+        .iter()
+        .filter_map(|(media, name)| {
+            let stats = pipeline
+                .by_name(name)?
+                .property("stats")
+                .ok()?
+                .get::<gstreamer::Structure>()
+                .ok()?;
+
+            Some(JitterBufferStats::from_structure(media, &stats))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_configures_the_jitter_buffer() {
+        let element = JitterBufferOptions::new()
+            .latency(Duration::from_millis(500))
+            .drop_on_latency(true)
+            .mode(JitterBufferMode::Buffer)
+            .element(VIDEO_JITTERBUFFER);
+
+        assert_eq!(
+            element.to_string(),
+            "rtpjitterbuffer name=video_jitterbuffer latency=500 drop-on-latency=true mode=buffer"
+        );
+    }
+
+    #[test]
+    fn it_reads_jitter_buffer_stats() {
+        gstreamer::init().unwrap();
+
+        let structure = gstreamer::Structure::builder("application/x-rtp-jitterbuffer-stats")
+            .field("num-pushed", 100u64)
+            .field("num-lost", 3u64)
+            .field("num-late", 2u64)
+            .field("num-duplicates", 1u64)
+            .build();
+
+        assert_eq!(
+            JitterBufferStats::from_structure("video", &structure),
+            JitterBufferStats {
+                media: "video".to_owned(),
+                pushed: 100,
+                lost: 3,
+                late: 2,
+                duplicates: 1,
+            }
+        );
+    }
+}
@@ -1,20 +1,43 @@
+pub mod appsink;
+#[cfg(feature = "async")]
+pub mod async_pipeline;
+pub mod codec;
+pub mod container;
 pub mod error;
+pub mod fec;
+pub mod jitter_buffer;
 pub mod main_loop;
+mod pause;
+pub mod pipeline_builder;
+pub mod record;
+pub mod recording_handle;
+pub mod rtcp;
+pub mod rtp_packet;
 pub mod rtp_stream;
 pub mod rtp_stream_record;
+pub mod rtp_tcp_record;
+pub mod rtp_tcp_server;
 pub mod rtp_udp_client_record;
+pub mod rtp_udp_hls;
 pub mod rtp_udp_server;
+pub mod sdp;
+pub mod srtp;
+pub mod tcp_source;
+pub mod udp_source;
 
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::Receiver;
 
 use crate::{
+    appsink::{
+        copy_bytes, keep_sample, set_sample_callbacks, share_bytes, AppsinkOptions, BufferReceiver,
+        Convert, DroppedBuffers,
+    },
     error::{MediaPipelineError, Result},
     main_loop::main_loop_simple,
 };
-use byte_slice_cast::AsSliceOf;
-use bytes::BytesMut;
-use gstreamer::{element_error, parse_launch, prelude::*, Element, Pipeline};
-use gstreamer_app::{AppSink, AppSinkCallbacks};
+use bytes::{Bytes, BytesMut};
+use gstreamer::{parse_launch, prelude::*, Element, Pipeline};
+use gstreamer_app::AppSink;
 use log::debug;
 
 // Initialize gstreamer and create a pipeline
@@ -38,85 +61,84 @@ fn element<T: IsA<Element>>(pipeline: &Pipeline, name: &'static str) -> Result<T
         .map_err(|_| MediaPipelineError::DowncastElementError(name))
 }
 
-fn appsink_pipeline(launch: &str, sender: Sender<BytesMut>) -> Result<gstreamer::Pipeline> {
-    let launch = format!("{} ! appsink name=sink", launch);
+// An appsink pipeline handing each converted sample to `deliver`
+fn appsink_pipeline<T, F>(
+    launch: &str,
+    options: &AppsinkOptions,
+    convert: Convert<T>,
+    deliver: F,
+) -> Result<gstreamer::Pipeline>
+where
+    F: FnMut(T) -> bool + Send + 'static,
+    T: 'static,
+{
+    let launch = format!("{} ! {}", launch, options.appsink("sink"));
 
     debug!("creating pipeline: {}", launch);
 
     let pipeline = create_pipeline(&launch)?;
     let appsink = element::<AppSink>(&pipeline, "sink")?;
 
-    // Getting data out of the appsink is done by setting callbacks on it.
-    // The appsink will then call those handlers, as soon as data is available.
-    appsink.set_callbacks(
-        AppSinkCallbacks::builder()
-            // Add a handler to the "new-sample" signal.
-            .new_sample(move |appsink| {
-                // Pull the sample in question out of the appsink's buffer.
-                let sample = appsink
-                    .pull_sample()
-                    .map_err(|_| gstreamer::FlowError::Eos)?;
-
-                let buffer = sample.buffer_owned().ok_or_else(|| {
-                    element_error!(
-                        appsink,
-                        gstreamer::ResourceError::Failed,
-                        ("Failed to get buffer from appsink")
-                    );
-
-                    gstreamer::FlowError::Error
-                })?;
-
-                // At this point, buffer is only a reference to an existing memory region somewhere.
-                // When we want to access its content, we have to map it while requesting the required
-                // mode of access (read, read/write).
-                // This type of abstraction is necessary, because the buffer in question might not be
-                // on the machine's main memory itself, but rather in the GPU's memory.
-                // So mapping the buffer makes the underlying memory region accessible to us.
-                // See: https://gstreamer.freedesktop.org/documentation/plugin-development/advanced/allocation.html
-                let map = buffer.map_readable().map_err(|_| {
-                    element_error!(
-                        appsink,
-                        gstreamer::ResourceError::Failed,
-                        ("Failed to map buffer readable")
-                    );
-
-                    gstreamer::FlowError::Error
-                })?;
-
-                // We know what format the data in the memory region has, since we requested
-                // it by setting the appsink's caps. So what we do here is interpret the
-                // memory region we mapped as an array of u8 packets.
-                let samples = map.as_slice_of::<u8>().map_err(|_| {
-                    element_error!(
-                        appsink,
-                        gstreamer::ResourceError::Failed,
-                        ("Failed to interprete buffer as S16 PCM")
-                    );
-
-                    gstreamer::FlowError::Error
-                })?;
-
-                // not an error, just the receiver is no longer around
-                if sender.send(BytesMut::from(samples)).is_err() {
-                    log::info!("Receiver not able to receive bytes from the rtp stream");
-                };
-
-                Ok(gstreamer::FlowSuccess::Ok)
-            })
-            .build(),
-    );
+    set_sample_callbacks(&appsink, convert, deliver);
     debug!("set pipeline callbacks");
 
     Ok(pipeline)
 }
 
-pub fn create_and_start_appsink_pipeline(launch: &str) -> Result<Receiver<BytesMut>> {
-    let (tx, rx) = bounded::<BytesMut>(100);
-    let pipline = appsink_pipeline(launch, tx);
+// Start an appsink pipeline sending each converted sample through the
+// channel set up by the options
+fn start_appsink_pipeline<T: Send + 'static>(
+    launch: &str,
+    options: &AppsinkOptions,
+    convert: Convert<T>,
+) -> Result<(BufferReceiver<T>, DroppedBuffers)> {
+    let (sender, rx) = options.channel();
+    let dropped = sender.dropped();
+    let pipline = appsink_pipeline(launch, options, convert, move |data| {
+        let sent = sender.send(data);
+
+        // not an error, just the receiver is no longer around
+        if !sent {
+            log::info!("Receiver not able to receive bytes, stopping the pipeline");
+        }
+
+        sent
+    });
     std::thread::spawn(move || match pipline.and_then(main_loop_simple) {
         Ok(_) => {}
         Err(err) => log::error!("pipeline error: {}", err),
     });
-    Ok(rx)
+    Ok((rx, dropped))
+}
+
+pub fn create_and_start_appsink_pipeline(launch: &str) -> Result<Receiver<BytesMut>> {
+    create_and_start_appsink_pipeline_with_options(launch, &AppsinkOptions::default())
+        .map(|(receiver, _)| receiver.into_receiver())
+}
+
+// Like `create_and_start_appsink_pipeline`, with the channel and appsink set up
+// by the options.  Also returns the count of buffers the drop policy dropped.
+pub fn create_and_start_appsink_pipeline_with_options(
+    launch: &str,
+    options: &AppsinkOptions,
+) -> Result<(BufferReceiver<BytesMut>, DroppedBuffers)> {
+    start_appsink_pipeline(launch, options, copy_bytes)
+}
+
+// Like `create_and_start_appsink_pipeline_with_options`, with bytes sharing the
+// memory of the GStreamer buffers instead of copies of it
+pub fn create_and_start_appsink_pipeline_bytes(
+    launch: &str,
+    options: &AppsinkOptions,
+) -> Result<(BufferReceiver<Bytes>, DroppedBuffers)> {
+    start_appsink_pipeline(launch, options, share_bytes)
+}
+
+// Like `create_and_start_appsink_pipeline_with_options`, with the samples
+// (buffer and caps) themselves
+pub fn create_and_start_appsink_pipeline_samples(
+    launch: &str,
+    options: &AppsinkOptions,
+) -> Result<(BufferReceiver<gstreamer::Sample>, DroppedBuffers)> {
+    start_appsink_pipeline(launch, options, keep_sample)
 }
@@ -1,7 +1,22 @@
-use crate::error::Result;
-use crossbeam_channel::{Receiver, Sender};
+use crate::error::{MediaPipelineError, Result};
+use crate::fec::{fec_stats, FecStats};
+use crate::jitter_buffer::{jitter_buffer_stats, JitterBufferStats};
+use crate::pause::Gate;
+use crate::rtcp::{source_stats, RtcpSourceStats};
+use crate::srtp::SrtpRejection;
+use crossbeam_channel::{bounded, select, tick, Receiver, RecvTimeoutError, Sender};
 use gstreamer::prelude::*;
 use gstreamer::Pipeline;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How long a stopped pipeline may take to write the end of its output
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the packet counts of jitter buffers and RTCP stats are reported
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
 
 // Commands sent from and to the main loop
 // TODO: add strum for auto string conversions
@@ -9,13 +24,174 @@ pub enum Command {
     // Stop ecording
     Stop,
 
-    // Recording has stopped
-    Stopped,
+    // Recording has stopped and the file is complete, with the path of the
+    // file (for segmented recordings the last segment)
+    Stopped(PathBuf),
+
+    // Recording was stopped but the file was not finished in time and may
+    // be incomplete
+    StopFailed(PathBuf),
+
+    // Hold the recording without ending the file, the paused time is left out
+    Pause,
+
+    // Recording has been paused
+    Paused,
+
+    // Continue a paused recording, video continues with the next keyframe
+    Resume,
+
+    // Recording has been resumed
+    Resumed,
+
+    // A segment of a segmented recording has been written completely
+    SegmentFinished(PathBuf),
+
+    // The pipeline failed, the main loop ends after this
+    Error(BusMessage),
+
+    // The pipeline reported a problem but keeps running
+    Warning(BusMessage),
+
+    // Packet counts of a stream received over the network, sent regularly
+    // and once more when the recording ends
+    JitterBufferStats(JitterBufferStats),
+
+    // Reception stats of a source of a stream received with RTCP, sent like
+    // the jitter buffer stats
+    RtcpStats(RtcpSourceStats),
+
+    // Packets recovered by the FEC of a stream, sent like the jitter buffer stats
+    FecStats(FecStats),
+
+    // Packets of an SRTP stream were dropped because they could not be
+    // authenticated, sent at most once a second per source
+    SrtpRejected(SrtpRejection),
+}
+
+// The GLib error domains GStreamer reports errors in
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BusErrorDomain {
+    Core,
+    Library,
+    Resource,
+    Stream,
+
+    // any other domain, e.g. "g-io-error-quark" for the socket errors of
+    // network sources, with the error's code in it
+    Other { domain: String, code: i32 },
+}
+
+// An error or warning posted on the bus of a pipeline
+#[derive(Clone, Debug, PartialEq)]
+pub struct BusMessage {
+    // path of the element that posted the message, e.g. `/GstPipeline:pipeline0/GstUDPSrc:udpsrc0`
+    pub source: Option<String>,
+    pub domain: BusErrorDomain,
+
+    // the code within the domain, e.g. a `gstreamer::ResourceError` for `BusErrorDomain::Resource`
+    pub code: i32,
+    pub message: String,
+    pub debug: Option<String>,
+}
+
+impl BusMessage {
+    fn new(source: Option<gstreamer::Object>, error: &glib::Error, debug: Option<String>) -> Self {
+        use glib::error::ErrorDomain;
+
+        let (domain, code) = if let Some(kind) = error.kind::<gstreamer::CoreError>() {
+            (BusErrorDomain::Core, kind.code())
+        } else if let Some(kind) = error.kind::<gstreamer::LibraryError>() {
+            (BusErrorDomain::Library, kind.code())
+        } else if let Some(kind) = error.kind::<gstreamer::ResourceError>() {
+            (BusErrorDomain::Resource, kind.code())
+        } else if let Some(kind) = error.kind::<gstreamer::StreamError>() {
+            (BusErrorDomain::Stream, kind.code())
+        } else {
+            let (domain, code) = raw_domain(error);
+
+            (BusErrorDomain::Other { domain, code }, code)
+        };
+
+        BusMessage {
+            source: source.map(|source| source.path_string().to_string()),
+            domain,
+            code,
+            message: error.to_string(),
+            debug,
+        }
+    }
 }
 
-// Creates a new main_loop that is able to send and receive Commands
+// The domain (the string of its quark) and code of an error, which glib only
+// hands out for the domains there are types for
+fn raw_domain(error: &glib::Error) -> (String, i32) {
+    use glib::translate::{FromGlib, ToGlibPtr};
+
+    let raw: *const glib::ffi::GError = error.to_glib_none().0;
+
+    // the GError is valid and not modified while the error is borrowed
+    unsafe {
+        let domain = glib::Quark::from_glib((*raw).domain);
+
+        (domain.to_string().to_owned(), (*raw).code)
+    }
+}
+
+impl std::fmt::Display for BusMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{}: {}", source, self.message)?,
+            None => write!(f, "{}", self.message)?,
+        }
+
+        match &self.debug {
+            Some(debug) => write!(f, " ({})", debug),
+            None => Ok(()),
+        }
+    }
+}
+
+// The result of a recording, if the event ends it
+pub(crate) fn outcome(event: &Command) -> Option<Result<PathBuf>> {
+    match event {
+        Command::Stopped(path) => Some(Ok(path.clone())),
+        Command::StopFailed(path) => Some(Err(MediaPipelineError::StopError(format!(
+            "{:?} may be incomplete",
+            path
+        )))),
+        Command::Error(message) => Some(Err(MediaPipelineError::BusError(message.to_string()))),
+        _ => None,
+    }
+}
+
+// The location of a segment splitmuxsink closed, if the message is about one
+fn finished_segment(structure: Option<&gstreamer::StructureRef>) -> Option<PathBuf> {
+    let structure = structure.filter(|s| s.name() == "splitmuxsink-fragment-closed")?;
+
+    structure.get::<String>("location").ok().map(PathBuf::from)
+}
+
+// Send the stats of the jitter buffers and RTP sessions in the pipeline
+fn send_stats(pipeline: &Pipeline, sender: &Sender<Command>) {
+    let stats = jitter_buffer_stats(pipeline)
+        .into_iter()
+        .map(Command::JitterBufferStats)
+        .chain(source_stats(pipeline).into_iter().map(Command::RtcpStats))
+        .chain(fec_stats(pipeline).into_iter().map(Command::FecStats));
+
+    for command in stats {
+        if let Err(error) = sender.send(command) {
+            log::error!("Error sending stats from the main loop: {:?}", error)
+        }
+    }
+}
+
+// Creates a new main_loop that is able to send and receive Commands,
+// writing its pipeline's output to a path
 pub(crate) fn main_loop(
     pipeline: Pipeline,
+    output: PathBuf,
     inbound_receiver: Receiver<Command>,
     outbound_sender: Sender<Command>,
 ) -> Result<glib::MainLoop> {
@@ -28,32 +204,94 @@ pub(crate) fn main_loop(
         .expect("Pipeline without bus. Shouldn't happen!");
 
     let pipeline_weak = pipeline.downgrade();
+    let bus_pipeline_weak = pipeline.downgrade();
+    let gate = Gate::attach(&pipeline);
+    let main_loop_quit = main_loop.clone();
+    let command_sender = outbound_sender.clone();
+    let bus_sender = outbound_sender.clone();
     let log_command = |command: &str| log::info!("received {} in main loop", command);
 
-    // listen for commands
-    std::thread::spawn(move || {
-        while let Ok(command) = inbound_receiver.recv() {
-            match command {
-                Command::Stop => {
-                    log_command("Command::Stop");
-
-                    if let Some(pipeline) = pipeline_weak.upgrade() {
-                        log::info!("sending EOS");
-
-                        pipeline.send_event(gstreamer::event::Eos::new());
-
-                        if let Err(error) = outbound_sender.send(Command::Stopped) {
-                            log::error!(
-                                "Error sending Command:Stopped from the main loop: {:?}",
-                                error
-                            )
-                        }
-                    } else {
-                        log::error!("Could not upgrade pipeline_weak in main loop");
+    // dropped when the main loop has finished
+    let (done_sender, done_receiver) = bounded::<()>(0);
+    let eos = Arc::new(AtomicBool::new(false));
+    let eos_received = eos.clone();
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let stop_timed_out = timed_out.clone();
+    let last_segment = Arc::new(Mutex::new(None));
+    let last_segment_finished = last_segment.clone();
+    let stats_ticker = tick(STATS_INTERVAL);
+
+    // listen for commands until the main loop has finished
+    let listener = std::thread::spawn(move || loop {
+        let command = select! {
+            recv(inbound_receiver) -> command => match command {
+                Ok(command) => command,
+                Err(_) => break,
+            },
+            recv(done_receiver) -> _ => break,
+            recv(stats_ticker) -> _ => {
+                if let Some(pipeline) = pipeline_weak.upgrade() {
+                    send_stats(&pipeline, &command_sender);
+                }
+
+                continue;
+            }
+        };
+        match command {
+            Command::Stop => {
+                log_command("Command::Stop");
+
+                if let Some(pipeline) = pipeline_weak.upgrade() {
+                    log::info!("sending EOS");
+
+                    pipeline.send_event(gstreamer::event::Eos::new());
+                } else {
+                    log::error!("Could not upgrade pipeline_weak in main loop");
+                    continue;
+                }
+
+                // the EOS reaching the bus ends the main loop once the output is written
+                if let Err(RecvTimeoutError::Timeout) = done_receiver.recv_timeout(STOP_TIMEOUT) {
+                    log::error!("Pipeline did not finish within {:?}", STOP_TIMEOUT);
+                    stop_timed_out.store(true, Ordering::SeqCst);
+                    main_loop_quit.quit();
+                }
+
+                break;
+            }
+            Command::Pause => {
+                log_command("Command::Pause");
+
+                if let Some(gate) = &gate {
+                    gate.pause();
+
+                    if let Err(error) = command_sender.send(Command::Paused) {
+                        log::error!(
+                            "Error sending Command:Paused from the main loop: {:?}",
+                            error
+                        )
                     }
+                } else {
+                    log::error!("The pipeline of the main loop cannot be paused");
                 }
-                _ => log::error!("Unhandled command"),
             }
+            Command::Resume => {
+                log_command("Command::Resume");
+
+                if let Some(gate) = &gate {
+                    gate.resume();
+
+                    if let Err(error) = command_sender.send(Command::Resumed) {
+                        log::error!(
+                            "Error sending Command:Resumed from the main loop: {:?}",
+                            error
+                        )
+                    }
+                } else {
+                    log::error!("The pipeline of the main loop cannot be resumed");
+                }
+            }
+            _ => log::error!("Unhandled command"),
         }
     });
 
@@ -64,22 +302,79 @@ pub(crate) fn main_loop(
         use gstreamer::MessageView;
         let main_loop = &main_loop_clone;
 
-        let _view = match msg.view() {
+        match msg.view() {
             MessageView::Eos(..) => {
                 log::info!("received EOS");
+                eos_received.store(true, Ordering::SeqCst);
                 // An EndOfStream event was sent to the pipeline, so we tell our main loop
                 // to stop execution here.
                 main_loop.quit()
             }
             MessageView::Error(err) => {
-                log::error!(
-                    "Error from {:?}: {} ({:?})",
-                    err.src().map(|s| s.path_string()),
-                    err.error(),
-                    err.debug()
-                );
+                let message = BusMessage::new(err.src(), &err.error(), err.debug());
+
+                log::error!("Error from {}", message);
+
+                if let Err(error) = bus_sender.send(Command::Error(message)) {
+                    log::error!(
+                        "Error sending Command:Error from the main loop: {:?}",
+                        error
+                    )
+                }
+
                 main_loop.quit();
             }
+            MessageView::Warning(warning) => {
+                let message = BusMessage::new(warning.src(), &warning.error(), warning.debug());
+
+                log::warn!("Warning from {}", message);
+
+                if let Err(error) = bus_sender.send(Command::Warning(message)) {
+                    log::error!(
+                        "Error sending Command:Warning from the main loop: {:?}",
+                        error
+                    )
+                }
+            }
+            MessageView::Element(element) => {
+                if let Some(location) = finished_segment(element.structure()) {
+                    log::info!("finished segment {:?}", location);
+
+                    // segments of a sink inside a bin (like an HLS sink) are
+                    // not the files of the output
+                    let parent = element.src().and_then(|src| src.parent());
+                    let pipeline = bus_pipeline_weak.upgrade();
+
+                    if parent.as_ref()
+                        == pipeline
+                            .as_ref()
+                            .map(|p| p.upcast_ref::<gstreamer::Object>())
+                    {
+                        *last_segment_finished.lock().unwrap() = Some(location.clone());
+                    }
+
+                    if let Err(error) = bus_sender.send(Command::SegmentFinished(location)) {
+                        log::error!(
+                            "Error sending Command:SegmentFinished from the main loop: {:?}",
+                            error
+                        )
+                    }
+                } else if let Some(rejection) = SrtpRejection::from_structure(element.structure()) {
+                    log::warn!(
+                        "{} SRTP packets of {} source {:x} rejected",
+                        rejection.packets,
+                        rejection.media,
+                        rejection.ssrc
+                    );
+
+                    if let Err(error) = bus_sender.send(Command::SrtpRejected(rejection)) {
+                        log::error!(
+                            "Error sending Command:SrtpRejected from the main loop: {:?}",
+                            error
+                        )
+                    }
+                }
+            }
             _ => (),
         };
 
@@ -89,8 +384,42 @@ pub(crate) fn main_loop(
 
     main_loop.run();
 
+    // releases the watch's senders, so receivers see the channel disconnect
+    // once the main loop is done
+    if let Err(error) = bus.remove_watch() {
+        log::error!(
+            "Could not remove the bus watch of the main loop: {:?}",
+            error
+        );
+    }
+
+    drop(done_sender);
+
+    if listener.join().is_err() {
+        log::error!("Command listener of the main loop panicked");
+    }
+
+    send_stats(&pipeline, &outbound_sender);
     pipeline.set_state(gstreamer::State::Null)?;
 
+    let output = last_segment.lock().unwrap().take().unwrap_or(output);
+    let event = if eos.load(Ordering::SeqCst) {
+        Some(Command::Stopped(output))
+    } else if timed_out.load(Ordering::SeqCst) {
+        Some(Command::StopFailed(output))
+    } else {
+        None
+    };
+
+    if let Some(event) = event {
+        if let Err(error) = outbound_sender.send(event) {
+            log::error!(
+                "Error sending the result of stopping the main loop: {:?}",
+                error
+            )
+        }
+    }
+
     Ok(main_loop)
 }
 
@@ -108,13 +437,17 @@ pub(crate) fn main_loop_simple(pipeline: gstreamer::Pipeline) -> Result<()> {
         match msg.view() {
             MessageView::Eos(..) => break,
             MessageView::Error(err) => {
+                let message = BusMessage::new(err.src(), &err.error(), err.debug());
+
+                log::error!("Error from {}", message);
                 pipeline.set_state(gstreamer::State::Null)?;
-                log::error!(
-                    "Error from {:?}: {} ({:?})",
-                    err.src().map(|s| s.path_string()),
-                    err.error(),
-                    err.debug()
-                );
+
+                return Err(MediaPipelineError::BusError(message.to_string()));
+            }
+            MessageView::Warning(warning) => {
+                let message = BusMessage::new(warning.src(), &warning.error(), warning.debug());
+
+                log::warn!("Warning from {}", message);
             }
             _ => (),
         }
@@ -126,4 +459,33 @@ pub(crate) fn main_loop_simple(pipeline: gstreamer::Pipeline) -> Result<()> {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+
+    use super::*;
+    use glib::error::ErrorDomain;
+
+    #[test]
+    fn it_describes_bus_errors() {
+        let error = glib::Error::new(gstreamer::ResourceError::NotFound, "No such file");
+        let message = BusMessage::new(None, &error, Some("filesrc.c(42)".to_owned()));
+
+        assert_eq!(message.domain, BusErrorDomain::Resource);
+        assert_eq!(
+            <gstreamer::ResourceError as ErrorDomain>::from(message.code),
+            Some(gstreamer::ResourceError::NotFound)
+        );
+        assert_eq!(message.to_string(), "No such file (filesrc.c(42))");
+
+        let error = glib::Error::new(glib::FileError::Noent, "No such file");
+        let message = BusMessage::new(None, &error, None);
+
+        assert_eq!(
+            message.domain,
+            BusErrorDomain::Other {
+                domain: "g-file-error-quark".to_owned(),
+                code: glib::FileError::Noent.code(),
+            }
+        );
+        assert_eq!(message.code, glib::FileError::Noent.code());
+    }
+}
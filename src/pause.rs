@@ -0,0 +1,226 @@
+use crate::pipeline_builder::ElementSpec;
+use gstreamer::prelude::*;
+use gstreamer::Pipeline;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Names of the elements the streams of a recording pass right before the muxer
+pub(crate) const VIDEO_GATE: &str = "video_gate";
+pub(crate) const AUDIO_GATE: &str = "audio_gate";
+
+// The element a stream is paused at
+pub(crate) fn gate_element(name: &str) -> ElementSpec {
+    ElementSpec::new("identity").name(name)
+}
+
+// What passes the gates while a recording is paused and resumed.  The gap is
+// measured in buffer timestamps, so buffers in flight when the commands
+// arrive cannot make the timestamps go back.
+#[derive(Debug, Default)]
+struct GateState {
+    paused: bool,
+
+    // after resuming, video can only continue with a keyframe.  Until it comes
+    // audio is held as well.
+    resuming: bool,
+
+    // taken off the timestamps of later buffers, from the end of the last
+    // buffer before each pause to the keyframe after it
+    offset: Duration,
+
+    // the latest end (PTS and duration) of the buffers passed, not shifted
+    end: Option<Duration>,
+
+    // PTS of the keyframe the streams resumed with, buffers before it that
+    // are still in flight are dropped
+    resumed_at: Option<Duration>,
+}
+
+impl GateState {
+    fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.resuming = true;
+        }
+    }
+
+    // The offset to subtract from a buffer's timestamps, or None to drop it
+    fn pass(
+        &mut self,
+        video: bool,
+        keyframe: bool,
+        pts: Option<Duration>,
+        duration: Option<Duration>,
+    ) -> Option<Duration> {
+        if self.paused {
+            return None;
+        }
+
+        if self.resuming {
+            if !(video && keyframe) {
+                return None;
+            }
+
+            if let (Some(pts), Some(end)) = (pts, self.end) {
+                self.offset += pts.saturating_sub(end);
+            }
+
+            self.resuming = false;
+            self.resumed_at = pts;
+        }
+
+        if let Some(pts) = pts {
+            if matches!(self.resumed_at, Some(resumed_at) if pts < resumed_at) {
+                return None;
+            }
+
+            let end = pts + duration.unwrap_or_default();
+
+            self.end = Some(self.end.map_or(end, |last| last.max(end)));
+        }
+
+        Some(self.offset)
+    }
+}
+
+// Holds the streams of a recording while paused without ending the file,
+// later buffers are moved back by the paused time so the recording has no gap
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Gate {
+    state: Arc<Mutex<GateState>>,
+}
+
+impl Gate {
+    // Attach to the gate elements of a pipeline, None if it has none
+    pub(crate) fn attach(pipeline: &Pipeline) -> Option<Self> {
+        let gate = Gate::default();
+        let mut attached = false;
+
+        for (name, video) in [(VIDEO_GATE, true), (AUDIO_GATE, false)] {
+            let pad = match pipeline
+                .by_name(name)
+                .and_then(|gate| gate.static_pad("src"))
+            {
+                Some(pad) => pad,
+                None => continue,
+            };
+            let state = gate.state.clone();
+
+            pad.add_probe(gstreamer::PadProbeType::BUFFER, move |_, info| {
+                let buffer = match &mut info.data {
+                    Some(gstreamer::PadProbeData::Buffer(buffer)) => buffer,
+                    _ => return gstreamer::PadProbeReturn::Ok,
+                };
+                let keyframe = !buffer.flags().contains(gstreamer::BufferFlags::DELTA_UNIT);
+                let time = |time: Option<gstreamer::ClockTime>| {
+                    time.map(|time| Duration::from_nanos(time.nseconds()))
+                };
+                let offset = match state.lock().unwrap().pass(
+                    video,
+                    keyframe,
+                    time(buffer.pts()),
+                    time(buffer.duration()),
+                ) {
+                    Some(offset) => offset.as_nanos() as u64,
+                    None => return gstreamer::PadProbeReturn::Drop,
+                };
+
+                if offset > 0 {
+                    let buffer = buffer.make_mut();
+                    let shift = |time: gstreamer::ClockTime| {
+                        gstreamer::ClockTime::from_nseconds(time.nseconds().saturating_sub(offset))
+                    };
+
+                    buffer.set_pts(buffer.pts().map(shift));
+                    buffer.set_dts(buffer.dts().map(shift));
+                }
+
+                gstreamer::PadProbeReturn::Ok
+            });
+
+            attached = true;
+        }
+
+        attached.then_some(gate)
+    }
+
+    pub(crate) fn pause(&self) {
+        self.state.lock().unwrap().pause();
+    }
+
+    pub(crate) fn resume(&self) {
+        self.state.lock().unwrap().resume();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn ms(millis: u64) -> Option<Duration> {
+        Some(Duration::from_millis(millis))
+    }
+
+    #[test]
+    fn it_holds_streams_while_paused() {
+        let frame = ms(40);
+        let mut state = GateState::default();
+
+        assert_eq!(state.pass(true, false, ms(0), frame), Some(Duration::ZERO));
+        assert_eq!(state.pass(true, false, ms(40), frame), Some(Duration::ZERO));
+
+        state.pause();
+
+        assert_eq!(state.pass(true, true, ms(80), frame), None);
+        assert_eq!(state.pass(false, true, ms(80), frame), None);
+
+        // pausing twice is one pause
+        state.pause();
+        state.resume();
+
+        // the keyframe follows the last buffer before the pause
+        assert_eq!(state.pass(true, true, ms(3080), frame), ms(3000));
+        assert_eq!(state.pass(false, true, ms(3080), ms(20)), ms(3000));
+        assert_eq!(state.pass(true, false, ms(3120), frame), ms(3000));
+
+        // resuming without pausing changes nothing
+        state.resume();
+
+        assert_eq!(state.pass(true, false, ms(3160), frame), ms(3000));
+    }
+
+    #[test]
+    fn it_closes_the_gap_until_the_keyframe() {
+        let frame = ms(40);
+        let mut state = GateState::default();
+
+        assert_eq!(state.pass(true, true, ms(0), frame), Some(Duration::ZERO));
+        assert_eq!(state.pass(false, true, ms(0), ms(60)), Some(Duration::ZERO));
+
+        state.pause();
+        state.resume();
+
+        // video waits for a keyframe, audio is held with it
+        assert_eq!(state.pass(true, false, ms(2000), frame), None);
+        assert_eq!(state.pass(false, true, ms(2010), ms(20)), None);
+
+        // the wait is taken off like the pause, from the end of the audio
+        // that reached furthest
+        assert_eq!(state.pass(true, true, ms(2500), frame), ms(2440));
+
+        // audio still in flight from before the keyframe would go back in time
+        assert_eq!(state.pass(false, true, ms(2480), ms(20)), None);
+        assert_eq!(state.pass(false, true, ms(2500), ms(20)), ms(2440));
+
+        // a second pause adds to the offset
+        state.pause();
+        state.resume();
+
+        assert_eq!(state.pass(true, true, ms(4540), frame), ms(4440));
+    }
+}
@@ -0,0 +1,819 @@
+use crate::error::{MediaPipelineError, Result};
+use gstreamer::prelude::*;
+use gstreamer::{Caps, Element, PadDirection, PadPresence, Pipeline};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Mutex;
+
+// A typed property (or caps field) value
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropertyValue {
+    Str(String),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    Fraction(i32, i32),
+
+    // An enum or flags nick (e.g. `tune=zerolatency`), rendered unquoted, so
+    // only letters, digits, `-` and `_` are accepted
+    Nick(String),
+}
+
+impl PropertyValue {
+    // Nicks are not quoted, so they may only hold the characters of enum and
+    // flags nicks
+    fn validate(&self) -> Result<()> {
+        match self {
+            PropertyValue::Nick(value) if !is_nick(value) => {
+                Err(invalid(format!("invalid nick {:?}", value)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Renders the value as an element property in a launch string
+    fn launch_value(&self) -> String {
+        match self {
+            PropertyValue::Str(value) => quote(value),
+            PropertyValue::Int(value) => value.to_string(),
+            PropertyValue::UInt(value) => value.to_string(),
+            PropertyValue::Bool(value) => value.to_string(),
+            PropertyValue::Fraction(num, den) => format!("{}/{}", num, den),
+            PropertyValue::Nick(value) => value.to_owned(),
+        }
+    }
+
+    // Deserializes the value into a property of the given type, as
+    // parse_launch does with the unquoted launch value
+    fn property_value(&self, value_type: glib::Type) -> Option<glib::Value> {
+        match self {
+            PropertyValue::Str(value) if value_type == glib::Type::STRING => Some(value.to_value()),
+            PropertyValue::Str(value) => glib::Value::deserialize(value.as_str(), value_type).ok(),
+            value => glib::Value::deserialize(value.launch_value().as_str(), value_type).ok(),
+        }
+    }
+
+    // Renders the value as a typed caps field
+    fn caps_value(&self) -> String {
+        match self {
+            PropertyValue::Str(value) => format!("(string){}", quote(value)),
+            PropertyValue::Int(value) => format!("(int){}", value),
+            PropertyValue::UInt(value) => format!("(uint){}", value),
+            PropertyValue::Bool(value) => format!("(boolean){}", value),
+            PropertyValue::Fraction(num, den) => format!("(fraction){}/{}", num, den),
+            PropertyValue::Nick(value) => value.to_owned(),
+        }
+    }
+}
+
+impl From<&str> for PropertyValue {
+    fn from(value: &str) -> Self {
+        PropertyValue::Str(value.to_owned())
+    }
+}
+
+impl From<String> for PropertyValue {
+    fn from(value: String) -> Self {
+        PropertyValue::Str(value)
+    }
+}
+
+impl From<&String> for PropertyValue {
+    fn from(value: &String) -> Self {
+        PropertyValue::Str(value.to_owned())
+    }
+}
+
+impl From<bool> for PropertyValue {
+    fn from(value: bool) -> Self {
+        PropertyValue::Bool(value)
+    }
+}
+
+impl From<CapsSpec> for PropertyValue {
+    fn from(caps: CapsSpec) -> Self {
+        PropertyValue::Str(caps.to_string())
+    }
+}
+
+macro_rules! impl_from_int {
+    ($variant:ident, $target:ty, $($source:ty),*) => {
+        $(
+            impl From<$source> for PropertyValue {
+                fn from(value: $source) -> Self {
+                    PropertyValue::$variant(value as $target)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(Int, i64, i8, i16, i32, i64);
+impl_from_int!(UInt, u64, u8, u16, u32, u64);
+
+// Quote a string value if parse_launch would otherwise split or misread it
+fn quote(value: &str) -> String {
+    let is_plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:+@%".contains(c));
+
+    if is_plain {
+        return value.to_owned();
+    }
+
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+
+    format!("\"{}\"", escaped)
+}
+
+fn is_nick(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub(crate) fn is_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// A single element in a pipeline, e.g. `udpsrc port=5000`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElementSpec {
+    factory: String,
+    name: Option<String>,
+    properties: Vec<(String, PropertyValue)>,
+}
+
+impl ElementSpec {
+    pub fn new(factory: &str) -> Self {
+        ElementSpec {
+            factory: factory.to_owned(),
+            name: None,
+            properties: Vec::new(),
+        }
+    }
+
+    // Name the element so it can be retrieved from the pipeline or linked to
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn property(mut self, key: &str, value: impl Into<PropertyValue>) -> Self {
+        self.properties.push((key.to_owned(), value.into()));
+        self
+    }
+
+    pub fn factory(&self) -> &str {
+        &self.factory
+    }
+
+    pub fn element_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // Render the properties as a serialized GstStructure, for elements that take
+    // another element's properties (e.g. splitmuxsink's muxer-properties)
+    pub(crate) fn properties_structure(&self, name: &str) -> Option<String> {
+        if self.properties.is_empty() {
+            return None;
+        }
+
+        let fields = self
+            .properties
+            .iter()
+            .map(|(key, value)| format!(", {}={}", key, value.caps_value()))
+            .collect::<String>();
+
+        Some(format!("{}{}", name, fields))
+    }
+
+    // Create the element and set its properties
+    fn make(&self) -> Result<Element> {
+        let element = gstreamer::ElementFactory::make(&self.factory, self.name.as_deref())?;
+
+        for (key, value) in &self.properties {
+            let property = element
+                .find_property(key.as_str())
+                .ok_or_else(|| invalid(format!("{} has no property {}", self.factory, key)))?;
+            let property_value = value.property_value(property.value_type()).ok_or_else(|| {
+                invalid(format!(
+                    "invalid value {} for {} on {}",
+                    value.launch_value(),
+                    key,
+                    self.factory
+                ))
+            })?;
+
+            element.set_property_from_value(key.as_str(), &property_value)?;
+        }
+
+        Ok(element)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !is_identifier(&self.factory) {
+            return Err(invalid(format!(
+                "invalid element factory {:?}",
+                self.factory
+            )));
+        }
+
+        if let Some(name) = &self.name {
+            if !is_identifier(name) {
+                return Err(invalid(format!("invalid element name {:?}", name)));
+            }
+        }
+
+        for (key, value) in &self.properties {
+            if !is_identifier(key) {
+                return Err(invalid(format!(
+                    "invalid property {:?} on {}",
+                    key, self.factory
+                )));
+            }
+
+            value.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for ElementSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.factory)?;
+
+        if let Some(name) = &self.name {
+            write!(f, " name={}", name)?;
+        }
+
+        for (key, value) in &self.properties {
+            write!(f, " {}={}", key, value.launch_value())?;
+        }
+
+        Ok(())
+    }
+}
+
+// A caps filter, e.g. `application/x-rtp, media=(string)video`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapsSpec {
+    media_type: String,
+    fields: Vec<(String, PropertyValue)>,
+}
+
+impl CapsSpec {
+    pub fn new(media_type: &str) -> Self {
+        CapsSpec {
+            media_type: media_type.to_owned(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(mut self, key: &str, value: impl Into<PropertyValue>) -> Self {
+        self.fields.push((key.to_owned(), value.into()));
+        self
+    }
+
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut parts = self.media_type.splitn(2, '/');
+        let valid_media_type = matches!(
+            (parts.next(), parts.next()),
+            (Some(kind), Some(subtype)) if is_identifier(kind) && is_identifier(subtype)
+        );
+
+        if !valid_media_type {
+            return Err(invalid(format!("invalid media type {:?}", self.media_type)));
+        }
+
+        for (key, value) in &self.fields {
+            if !is_identifier(key) {
+                return Err(invalid(format!("invalid caps field {:?}", key)));
+            }
+
+            value.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for CapsSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.media_type)?;
+
+        for (key, value) in &self.fields {
+            write!(f, ", {}={}", key, value.caps_value())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Stage {
+    Element(ElementSpec),
+    Caps(CapsSpec),
+
+    // A reference to a named element, optionally to one of its pads
+    Pad(String, Option<String>),
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Element(element) => write!(f, "{}", element),
+            Stage::Caps(caps) => write!(f, "{}", caps),
+            Stage::Pad(element, pad) => write!(f, "{}.{}", element, pad.as_deref().unwrap_or("")),
+        }
+    }
+}
+
+// A linear run of linked elements.  Chains can start from or end at a named
+// element's pad, which is how branches (tees, muxers, rtpbin) are composed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Chain {
+    stages: Vec<Stage>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Chain::default()
+    }
+
+    // Start the chain from a named element, e.g. `rtpbin.` or `demux.video_0`
+    pub fn from_pad(element: &str, pad: Option<&str>) -> Self {
+        Chain {
+            stages: vec![Stage::Pad(element.to_owned(), pad.map(str::to_owned))],
+        }
+    }
+
+    pub fn element(mut self, element: ElementSpec) -> Self {
+        self.stages.push(Stage::Element(element));
+        self
+    }
+
+    pub fn caps(mut self, caps: CapsSpec) -> Self {
+        self.stages.push(Stage::Caps(caps));
+        self
+    }
+
+    // End the chain by linking into a named element, e.g. `mux.` or `mux.video_0`
+    pub fn to_pad(mut self, element: &str, pad: Option<&str>) -> Self {
+        self.stages
+            .push(Stage::Pad(element.to_owned(), pad.map(str::to_owned)));
+        self
+    }
+
+    // Append another chain's stages to this one
+    pub fn append(mut self, other: Chain) -> Self {
+        self.stages.extend(other.stages);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    // The factories of the chain's elements, in order
+    pub(crate) fn factories(&self) -> Vec<&str> {
+        self.elements().map(ElementSpec::factory).collect()
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &ElementSpec> {
+        self.stages.iter().filter_map(|stage| match stage {
+            Stage::Element(element) => Some(element),
+            _ => None,
+        })
+    }
+
+    // Link the chain's elements, created in order, and the named elements the
+    // chain starts from or ends at
+    fn link(&self, pipeline: &Pipeline, elements: Vec<Element>) -> Result<()> {
+        let mut elements = elements.into_iter();
+        let mut source: Option<(Element, Option<&str>)> = None;
+        let mut filter = None;
+
+        for stage in &self.stages {
+            let (element, pad) = match stage {
+                Stage::Element(spec) => (
+                    elements
+                        .next()
+                        .ok_or_else(|| invalid(format!("{} was not created", spec.factory())))?,
+                    None,
+                ),
+                Stage::Pad(name, pad) => (
+                    pipeline
+                        .by_name(name)
+                        .ok_or_else(|| invalid(format!("reference to unknown element {}", name)))?,
+                    pad.as_deref(),
+                ),
+                Stage::Caps(caps) => {
+                    let parsed = caps
+                        .to_string()
+                        .parse::<Caps>()
+                        .map_err(|_| invalid(format!("invalid caps {}", caps)))?;
+
+                    filter = Some(parsed);
+                    continue;
+                }
+            };
+
+            if let Some((src, src_pad)) = source.take() {
+                link(&src, src_pad, &element, pad, filter.take())?;
+            }
+
+            source = Some((element, pad));
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let last = self.stages.len().saturating_sub(1);
+
+        if self.elements().next().is_none() {
+            return Err(invalid("chain without elements".to_owned()));
+        }
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            match stage {
+                Stage::Element(element) => element.validate()?,
+                Stage::Caps(caps) => {
+                    caps.validate()?;
+
+                    if index == 0 || index == last {
+                        return Err(invalid(format!(
+                            "caps {} are not between two elements",
+                            caps
+                        )));
+                    }
+                }
+                Stage::Pad(element, _) if index != 0 && index != last => {
+                    return Err(invalid(format!(
+                        "reference to {} must start or end a chain",
+                        element
+                    )));
+                }
+                Stage::Pad(..) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages = self
+            .stages
+            .iter()
+            .map(Stage::to_string)
+            .collect::<Vec<_>>()
+            .join(" ! ");
+
+        write!(f, "{}", stages)
+    }
+}
+
+// Composes chains into a validated pipeline
+//
+// ```ignore
+// let pipeline = PipelineBuilder::new()
+//     .chain(
+//         Chain::new()
+//             .element(ElementSpec::new("videotestsrc"))
+//             .element(ElementSpec::new("filesink").property("location", "my video.raw")),
+//     )
+//     .build()?;
+// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipelineBuilder {
+    chains: Vec<Chain>,
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        PipelineBuilder::default()
+    }
+
+    pub fn chain(mut self, chain: Chain) -> Self {
+        self.chains.push(chain);
+        self
+    }
+
+    // Check the structure of the pipeline without touching GStreamer
+    pub fn validate(&self) -> Result<()> {
+        if self.chains.is_empty() {
+            return Err(invalid("pipeline without chains".to_owned()));
+        }
+
+        let mut names = HashSet::new();
+
+        for chain in &self.chains {
+            chain.validate()?;
+
+            for name in chain.elements().filter_map(ElementSpec::element_name) {
+                if !names.insert(name) {
+                    return Err(invalid(format!("duplicate element name {}", name)));
+                }
+            }
+        }
+
+        for chain in &self.chains {
+            for stage in &chain.stages {
+                if let Stage::Pad(element, _) = stage {
+                    if !names.contains(element.as_str()) {
+                        return Err(invalid(format!("reference to unknown element {}", element)));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Render the gst-launch description of the pipeline.  `build` does not
+    // parse it, it is only for logging and debugging (e.g. with gst-launch-1.0).
+    pub fn launch(&self) -> Result<String> {
+        self.validate()?;
+
+        let launch = self
+            .chains
+            .iter()
+            .map(Chain::to_string)
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        Ok(launch)
+    }
+
+    // Validate the pipeline, check that every element is installed, then create
+    // the elements, set their properties and link them
+    pub fn build(&self) -> Result<Pipeline> {
+        let launch = self.launch()?;
+
+        gstreamer::init()?;
+
+        for element in self.chains.iter().flat_map(Chain::elements) {
+            if gstreamer::ElementFactory::find(element.factory()).is_none() {
+                return Err(MediaPipelineError::MissingElementError(
+                    element.factory().to_owned(),
+                ));
+            }
+        }
+
+        log::debug!("creating pipeline: {}", launch);
+
+        let pipeline = Pipeline::new(None);
+
+        // every element is added before linking, chains may link to named
+        // elements of later chains
+        let elements = self
+            .chains
+            .iter()
+            .map(|chain| {
+                chain
+                    .elements()
+                    .map(|spec| {
+                        let element = spec.make()?;
+
+                        pipeline.add(&element)?;
+                        Ok(element)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (chain, elements) in self.chains.iter().zip(elements) {
+            chain.link(&pipeline, elements)?;
+        }
+
+        Ok(pipeline)
+    }
+}
+
+// Link two elements the way parse_launch does: right away if the pads exist or
+// can be requested, otherwise once the source adds a sometimes pad that links
+// (e.g. rtpbin's receive pads, which appear with the first packets)
+fn link(
+    src: &Element,
+    src_pad: Option<&str>,
+    sink: &Element,
+    sink_pad: Option<&str>,
+    filter: Option<Caps>,
+) -> Result<()> {
+    if link_pads(src, src_pad, sink, sink_pad, filter.as_ref()).is_ok() {
+        return Ok(());
+    }
+
+    let has_sometimes_pads = src.pad_template_list().iter().any(|template| {
+        template.direction() == PadDirection::Src && template.presence() == PadPresence::Sometimes
+    });
+
+    if !has_sometimes_pads {
+        return Err(MediaPipelineError::LinkError(format!(
+            "{}.{} to {}.{}",
+            src.name(),
+            src_pad.unwrap_or(""),
+            sink.name(),
+            sink_pad.unwrap_or("")
+        )));
+    }
+
+    let src_pad = src_pad.map(str::to_owned);
+    let sink_pad = sink_pad.map(str::to_owned);
+    let sink = sink.downgrade();
+    let linked = Mutex::new(false);
+
+    src.connect_pad_added(move |src, _| {
+        let mut linked = linked.lock().unwrap();
+
+        if let (false, Some(sink)) = (*linked, sink.upgrade()) {
+            *linked = link_pads(
+                src,
+                src_pad.as_deref(),
+                &sink,
+                sink_pad.as_deref(),
+                filter.as_ref(),
+            )
+            .is_ok();
+        }
+    });
+
+    Ok(())
+}
+
+fn link_pads(
+    src: &Element,
+    src_pad: Option<&str>,
+    sink: &Element,
+    sink_pad: Option<&str>,
+    filter: Option<&Caps>,
+) -> std::result::Result<(), glib::BoolError> {
+    match filter {
+        Some(filter) => src.link_pads_filtered(src_pad, sink, sink_pad, filter),
+        None => src.link_pads(src_pad, sink, sink_pad),
+    }
+}
+
+fn invalid(message: String) -> MediaPipelineError {
+    MediaPipelineError::PipelineBuilderError(message)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_renders_a_launch_string() {
+        let launch = PipelineBuilder::new()
+            .chain(
+                Chain::new()
+                    .element(ElementSpec::new("udpsrc").property("port", 5000))
+                    .caps(
+                        CapsSpec::new("application/x-rtp")
+                            .field("media", "video")
+                            .field("clock-rate", 90000),
+                    )
+                    .element(ElementSpec::new("rtph264depay").name("pay0"))
+                    .element(ElementSpec::new("h264parse").property("config-interval", -1))
+                    .element(ElementSpec::new("mp4mux"))
+                    .element(
+                        ElementSpec::new("filesink").property("location", "my \"best\" video.mp4"),
+                    ),
+            )
+            .launch()
+            .unwrap();
+
+        assert_eq!(
+            launch,
+            "udpsrc port=5000 ! application/x-rtp, media=(string)video, clock-rate=(int)90000 \
+            ! rtph264depay name=pay0 ! h264parse config-interval=-1 ! mp4mux \
+            ! filesink location=\"my \\\"best\\\" video.mp4\""
+        );
+    }
+
+    #[test]
+    fn it_links_named_elements() {
+        let launch = PipelineBuilder::new()
+            .chain(Chain::new().element(ElementSpec::new("matroskamux").name("mux")))
+            .chain(
+                Chain::new()
+                    .element(ElementSpec::new("videotestsrc"))
+                    .to_pad("mux", Some("video_0")),
+            )
+            .launch()
+            .unwrap();
+
+        assert_eq!(launch, "matroskamux name=mux  videotestsrc ! mux.video_0");
+    }
+
+    #[test]
+    fn it_builds_and_links_the_elements() {
+        let pipeline = PipelineBuilder::new()
+            .chain(Chain::new().element(ElementSpec::new("fakesink").name("sink")))
+            .chain(
+                Chain::new()
+                    .element(
+                        ElementSpec::new("videotestsrc")
+                            .name("src")
+                            .property("num-buffers", 1)
+                            .property("pattern", PropertyValue::Nick("ball".into())),
+                    )
+                    .caps(CapsSpec::new("video/x-raw").field("width", 320))
+                    .to_pad("sink", None),
+            )
+            .build()
+            .unwrap();
+        let src = pipeline.by_name("src").unwrap();
+        let sink = pipeline.by_name("sink").unwrap();
+
+        assert_eq!(
+            src.property("num-buffers").unwrap().get::<i32>().unwrap(),
+            1
+        );
+        assert!(sink.static_pad("sink").unwrap().is_linked());
+
+        let unknown_property = PipelineBuilder::new().chain(
+            Chain::new()
+                .element(ElementSpec::new("videotestsrc").property("no-such-property", 1))
+                .element(ElementSpec::new("fakesink")),
+        );
+        let invalid_value = PipelineBuilder::new().chain(
+            Chain::new()
+                .element(ElementSpec::new("videotestsrc").property("num-buffers", "many"))
+                .element(ElementSpec::new("fakesink")),
+        );
+
+        for builder in [unknown_property, invalid_value].iter() {
+            assert!(matches!(
+                builder.build(),
+                Err(MediaPipelineError::PipelineBuilderError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_pipelines() {
+        let empty = PipelineBuilder::new();
+        let duplicate = PipelineBuilder::new()
+            .chain(Chain::new().element(ElementSpec::new("queue").name("q")))
+            .chain(Chain::new().element(ElementSpec::new("queue").name("q")));
+        let unknown = PipelineBuilder::new().chain(
+            Chain::new()
+                .element(ElementSpec::new("videotestsrc"))
+                .to_pad("mux", None),
+        );
+        let dangling_caps = PipelineBuilder::new().chain(
+            Chain::new()
+                .element(ElementSpec::new("videotestsrc"))
+                .caps(CapsSpec::new("video/x-raw")),
+        );
+        let bad_factory =
+            PipelineBuilder::new().chain(Chain::new().element(ElementSpec::new("fake ! sink")));
+        let bad_nick = PipelineBuilder::new().chain(
+            Chain::new().element(
+                ElementSpec::new("x264enc")
+                    .property("tune", PropertyValue::Nick("a ! fakesink".into())),
+            ),
+        );
+        let bad_caps_nick = PipelineBuilder::new().chain(
+            Chain::new()
+                .element(ElementSpec::new("videotestsrc"))
+                .caps(
+                    CapsSpec::new("video/x-raw")
+                        .field("format", PropertyValue::Nick("I420, width=1".into())),
+                )
+                .element(ElementSpec::new("fakesink")),
+        );
+
+        for builder in [
+            empty,
+            duplicate,
+            unknown,
+            dangling_caps,
+            bad_factory,
+            bad_nick,
+            bad_caps_nick,
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                builder.launch(),
+                Err(MediaPipelineError::PipelineBuilderError(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use crate::codec::{AudioCodec, PayloadTypeMap, RtpCodec, VideoCodec};
+use crate::container::Container;
+use crate::error::{MediaPipelineError, Result};
+use crate::fec::FecOptions;
+use crate::jitter_buffer::JitterBufferOptions;
+use crate::pause::{gate_element, AUDIO_GATE, VIDEO_GATE};
+use crate::pipeline_builder::{Chain, ElementSpec, PipelineBuilder};
+use crate::rtcp::RtcpOptions;
+use crate::sdp::{RtpFormat, SessionDescription};
+use crate::srtp::SrtpOptions;
+use std::time::Duration;
+
+// When to start a new file in a segmented recording.  A new segment starts at
+// the first keyframe after either limit is reached.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SegmentOptions {
+    max_duration: Option<Duration>,
+    max_bytes: Option<u64>,
+}
+
+impl SegmentOptions {
+    pub fn new() -> Self {
+        SegmentOptions::default()
+    }
+
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.max_duration = Some(max_duration);
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    // A splitmuxsink writing segments to a location pattern such as `recording-%05d.mp4`
+    fn sink(&self, pattern: &str, container: Container) -> Result<ElementSpec> {
+        if self.max_duration.is_none() && self.max_bytes.is_none() {
+            return Err(MediaPipelineError::RecordOptionsError(
+                "segments need a maximum duration or size".to_owned(),
+            ));
+        }
+
+        // splitmuxsink takes a zero limit as no limit at all
+        if self.max_duration == Some(Duration::ZERO) || self.max_bytes == Some(0) {
+            return Err(MediaPipelineError::RecordOptionsError(
+                "segment limits must be above zero".to_owned(),
+            ));
+        }
+
+        if !is_segment_pattern(pattern) {
+            return Err(MediaPipelineError::RecordOptionsError(format!(
+                "segment location {} has no %d placeholder",
+                pattern
+            )));
+        }
+
+        let muxer = container.muxer();
+        let mut sink = ElementSpec::new("splitmuxsink")
+            .property("location", pattern)
+            .property("muxer-factory", muxer.factory());
+
+        if let Some(properties) = muxer.properties_structure("properties") {
+            sink = sink.property("muxer-properties", properties);
+        }
+
+        if let Some(max_duration) = self.max_duration {
+            sink = sink.property("max-size-time", max_duration.as_nanos() as u64);
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            sink = sink.property("max-size-bytes", max_bytes);
+        }
+
+        Ok(sink)
+    }
+}
+
+// Whether a location contains exactly one printf style integer placeholder, e.g. `%05d`
+fn is_segment_pattern(pattern: &str) -> bool {
+    let mut chars = pattern.chars().peekable();
+    let mut placeholders = 0;
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+
+        if chars.next_if_eq(&'%').is_some() {
+            continue;
+        }
+
+        while chars.next_if(char::is_ascii_digit).is_some() {}
+
+        match chars.next() {
+            Some('d') | Some('u') => placeholders += 1,
+            _ => return false,
+        }
+    }
+
+    placeholders == 1
+}
+
+// What the recorders expect to receive and how they write it
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordOptions {
+    video_codec: VideoCodec,
+    audio_codec: Option<AudioCodec>,
+    container: Option<Container>,
+    segments: Option<SegmentOptions>,
+    payload_types: PayloadTypeMap,
+
+    // fmtp parameters of the video stream, e.g. from an SDP document
+    video_parameters: Vec<(String, String)>,
+
+    // for streams received over UDP
+    jitter_buffer: JitterBufferOptions,
+    rtcp: Option<RtcpOptions>,
+    audio_rtcp: Option<RtcpOptions>,
+    srtp: Option<SrtpOptions>,
+    audio_srtp: Option<SrtpOptions>,
+
+    // for streams received over UDP or from appsrc
+    fec: Option<FecOptions>,
+    audio_fec: Option<FecOptions>,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            video_codec: VideoCodec::H264,
+            audio_codec: None,
+            container: None,
+            segments: None,
+            payload_types: PayloadTypeMap::default(),
+            video_parameters: Vec::new(),
+            jitter_buffer: JitterBufferOptions::default(),
+            rtcp: None,
+            audio_rtcp: None,
+            srtp: None,
+            audio_srtp: None,
+            fec: None,
+            audio_fec: None,
+        }
+    }
+}
+
+impl RecordOptions {
+    pub fn new() -> Self {
+        RecordOptions::default()
+    }
+
+    // Take the video (and if supported, audio) format from the first video
+    // and audio sections of an SDP document
+    pub fn from_sdp(sdp: &SessionDescription) -> Result<Self> {
+        let format = sdp
+            .media("video")
+            .and_then(|media| media.format())
+            .ok_or_else(|| MediaPipelineError::SdpError("no video format".to_owned()))?;
+
+        if format.encoding_name.is_empty() {
+            return Err(MediaPipelineError::SdpError(format!(
+                "no rtpmap for payload type {}",
+                format.payload_type
+            )));
+        }
+
+        let codec = VideoCodec::from_encoding_name(&format.encoding_name)?;
+        let mut payload_types = PayloadTypeMap::new().set(codec, format.payload_type);
+        let mut options = RecordOptions::new().video_codec(codec);
+
+        options.video_parameters = format.fmtp.clone();
+
+        if let Some(format) = sdp.media("audio").and_then(|media| media.format()) {
+            match AudioCodec::from_encoding_name(&format.encoding_name) {
+                Ok(codec) => {
+                    payload_types = payload_types.set(codec, format.payload_type);
+                    options = options.audio_codec(codec);
+                }
+                Err(error) => log::warn!("Not recording audio: {}", error),
+            }
+        }
+
+        Ok(options.payload_types(payload_types))
+    }
+
+    pub fn video_codec(mut self, codec: VideoCodec) -> Self {
+        self.video_codec = codec;
+        self
+    }
+
+    // Record an audio stream next to the video
+    pub fn audio_codec(mut self, codec: AudioCodec) -> Self {
+        self.audio_codec = Some(codec);
+        self
+    }
+
+    // Write a specific container instead of the default for the codecs
+    pub fn container(mut self, container: Container) -> Self {
+        self.container = Some(container);
+        self
+    }
+
+    // Split the recording into segments, the filename passed to `record`
+    // then is a pattern such as `recording-%05d.mp4`
+    pub fn segments(mut self, segments: SegmentOptions) -> Self {
+        self.segments = Some(segments);
+        self
+    }
+
+    pub fn payload_types(mut self, payload_types: PayloadTypeMap) -> Self {
+        self.payload_types = payload_types;
+        self
+    }
+
+    // How streams received over UDP are reordered before recording
+    pub fn jitter_buffer(mut self, jitter_buffer: JitterBufferOptions) -> Self {
+        self.jitter_buffer = jitter_buffer;
+        self
+    }
+
+    // Receive the streams over UDP through an rtpbin, with RTCP for the video
+    pub fn rtcp(mut self, rtcp: RtcpOptions) -> Self {
+        self.rtcp = Some(rtcp);
+        self
+    }
+
+    // RTCP for the audio stream, which has its own RTP session
+    pub fn audio_rtcp(mut self, rtcp: RtcpOptions) -> Self {
+        self.audio_rtcp = Some(rtcp);
+        self
+    }
+
+    // Decrypt the video received over UDP as SRTP
+    pub fn srtp(mut self, srtp: SrtpOptions) -> Self {
+        self.srtp = Some(srtp);
+        self
+    }
+
+    // Decrypt the audio received over UDP as SRTP, it has keys of its own
+    pub fn audio_srtp(mut self, srtp: SrtpOptions) -> Self {
+        self.audio_srtp = Some(srtp);
+        self
+    }
+
+    // Recover lost video packets from the FEC packets sent along with them
+    pub fn fec(mut self, fec: FecOptions) -> Self {
+        self.fec = Some(fec);
+        self
+    }
+
+    // FEC of the audio stream, e.g. the RED audio browsers send
+    pub fn audio_fec(mut self, fec: FecOptions) -> Self {
+        self.audio_fec = Some(fec);
+        self
+    }
+
+    pub fn codec(&self) -> VideoCodec {
+        self.video_codec
+    }
+
+    pub fn audio(&self) -> Option<AudioCodec> {
+        self.audio_codec
+    }
+
+    // The chosen container, or the default one for the codecs
+    pub fn output_container(&self) -> Container {
+        self.container
+            .unwrap_or_else(|| Container::default_for(self.video_codec, self.audio_codec))
+    }
+
+    pub(crate) fn jitter_buffer_options(&self) -> &JitterBufferOptions {
+        &self.jitter_buffer
+    }
+
+    pub(crate) fn rtcp_options(&self) -> Option<&RtcpOptions> {
+        self.rtcp.as_ref()
+    }
+
+    pub(crate) fn audio_rtcp_options(&self) -> Option<&RtcpOptions> {
+        self.audio_rtcp.as_ref()
+    }
+
+    pub(crate) fn srtp_options(&self) -> Option<&SrtpOptions> {
+        self.srtp.as_ref()
+    }
+
+    pub(crate) fn audio_srtp_options(&self) -> Option<&SrtpOptions> {
+        self.audio_srtp.as_ref()
+    }
+
+    pub(crate) fn fec_options(&self) -> Option<&FecOptions> {
+        self.fec.as_ref()
+    }
+
+    pub(crate) fn audio_fec_options(&self) -> Option<&FecOptions> {
+        self.audio_fec.as_ref()
+    }
+
+    // The RTP format the video stream is expected in
+    pub fn video_format(&self) -> RtpFormat {
+        let mut format = self.format("video", &self.video_codec);
+
+        format.fmtp = self.video_parameters.clone();
+        format
+    }
+
+    // The RTP format the audio stream is expected in, if audio is recorded
+    pub fn audio_format(&self) -> Option<RtpFormat> {
+        self.audio_codec
+            .as_ref()
+            .map(|codec| self.format("audio", codec))
+    }
+
+    // The audio format for recording an audio source, which needs an audio codec
+    pub(crate) fn required_audio_format(&self) -> Result<RtpFormat> {
+        self.audio_format().ok_or_else(no_audio_codec)
+    }
+
+    fn format(&self, media: &str, codec: &impl RtpCodec) -> RtpFormat {
+        RtpFormat {
+            media: media.to_owned(),
+            payload_type: self.payload_types.get(codec),
+            encoding_name: codec.encoding_name().to_owned(),
+            clock_rate: codec.clock_rate(),
+            encoding_params: None,
+            fmtp: Vec::new(),
+        }
+    }
+}
+
+fn no_audio_codec() -> MediaPipelineError {
+    MediaPipelineError::RecordOptionsError("an audio source needs an audio codec".to_owned())
+}
+
+// Where the depayloaded streams go.  The chain starts with an element named
+// "mux" that the streams are linked to, on the given pads if the element does
+// not pick pads by caps.
+pub(crate) struct Sink {
+    pub(crate) chain: Chain,
+    pub(crate) container: Container,
+    pub(crate) video_pad: Option<&'static str>,
+    pub(crate) audio_pad: Option<&'static str>,
+}
+
+// Depayload, parse and mux the RTP sources into a file (or segments).  The sources
+// have to produce RTP with the caps of the video and audio formats of the options.
+pub(crate) fn recording_pipeline(
+    filename: &str,
+    options: &RecordOptions,
+    video_source: Chain,
+    audio_source: Option<Chain>,
+) -> Result<PipelineBuilder> {
+    let container = options.output_container();
+
+    // splitmuxsink needs to be linked to its video and audio pads explicitly,
+    // muxers pick a pad matching the caps
+    let sink = match &options.segments {
+        Some(segments) => Sink {
+            chain: Chain::new().element(segments.sink(filename, container)?.name("mux")),
+            container,
+            video_pad: Some("video"),
+            audio_pad: Some("audio_%u"),
+        },
+        None => Sink {
+            chain: Chain::new()
+                .element(container.muxer().name("mux"))
+                .element(ElementSpec::new("filesink").property("location", filename)),
+            container,
+            video_pad: None,
+            audio_pad: None,
+        },
+    };
+
+    depayload_pipeline(options, sink, video_source, audio_source)
+}
+
+// Depayload and parse the RTP sources into a sink, through the gates that
+// pause the recording
+pub(crate) fn depayload_pipeline(
+    options: &RecordOptions,
+    sink: Sink,
+    video_source: Chain,
+    audio_source: Option<Chain>,
+) -> Result<PipelineBuilder> {
+    let container = sink.container;
+    let unsupported = |codec: &str| {
+        MediaPipelineError::RecordOptionsError(format!("{:?} cannot store {}", container, codec))
+    };
+
+    if !container.supports_video(options.video_codec) {
+        return Err(unsupported(options.video_codec.encoding_name()));
+    }
+
+    let depayload = options.video_codec.depayload_chain();
+
+    options.video_codec.check_installed(&depayload)?;
+
+    let mut builder = PipelineBuilder::new().chain(sink.chain).chain(
+        video_source
+            .element(ElementSpec::new("queue"))
+            .append(depayload)
+            .element(gate_element(VIDEO_GATE))
+            .to_pad("mux", sink.video_pad),
+    );
+
+    if let Some(audio_source) = audio_source {
+        let codec = options.audio_codec.ok_or_else(no_audio_codec)?;
+
+        if !container.supports_audio(codec) {
+            return Err(unsupported(codec.encoding_name()));
+        }
+
+        builder = builder.chain(
+            audio_source
+                .element(ElementSpec::new("queue"))
+                .append(codec.depayload_chain())
+                .element(gate_element(AUDIO_GATE))
+                .to_pad("mux", sink.audio_pad),
+        );
+    } else if let Some(codec) = options.audio_codec {
+        log::warn!(
+            "No audio source, recording without {} audio",
+            codec.encoding_name()
+        );
+    }
+
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_takes_the_video_format_from_sdp() {
+        let sdp = "v=0\nm=video 5000 RTP/AVP 102\na=rtpmap:102 VP8/90000\n";
+        let options = RecordOptions::from_sdp(&SessionDescription::parse(sdp).unwrap()).unwrap();
+
+        assert_eq!(options.codec(), VideoCodec::Vp8);
+        assert_eq!(options.video_format().payload_type, 102);
+        assert_eq!(options.video_format().encoding_name, "VP8");
+    }
+
+    #[test]
+    fn it_muxes_audio_and_video() {
+        let sdp = "v=0\n\
+            m=video 5000 RTP/AVP 96\na=rtpmap:96 H264/90000\n\
+            m=audio 5002 RTP/AVP 0\n";
+        let options = RecordOptions::from_sdp(&SessionDescription::parse(sdp).unwrap()).unwrap();
+        let source =
+            |port: i32| Chain::new().element(ElementSpec::new("udpsrc").property("port", port));
+        let launch = recording_pipeline("call.mkv", &options, source(5000), Some(source(5002)))
+            .and_then(|builder| builder.launch())
+            .unwrap();
+
+        assert_eq!(options.audio(), Some(AudioCodec::Pcmu));
+        assert_eq!(
+            launch,
+            "matroskamux name=mux ! filesink location=call.mkv  \
+            udpsrc port=5000 ! queue ! rtph264depay ! h264parse config-interval=-1 ! identity name=video_gate ! mux.  \
+            udpsrc port=5002 ! queue ! rtppcmudepay ! identity name=audio_gate ! mux."
+        );
+    }
+
+    #[test]
+    fn it_records_segments() {
+        let options = RecordOptions::new()
+            .audio_codec(AudioCodec::Opus)
+            .container(Container::FragmentedMp4)
+            .segments(SegmentOptions::new().max_duration(Duration::from_secs(60)));
+        let source = || Chain::new().element(ElementSpec::new("appsrc"));
+        let launch = recording_pipeline("rec-%05d.mp4", &options, source(), Some(source()))
+            .and_then(|builder| builder.launch())
+            .unwrap();
+
+        assert_eq!(
+            launch,
+            "splitmuxsink name=mux location=rec-%05d.mp4 muxer-factory=mp4mux \
+            muxer-properties=\"properties, fragment-duration=(uint)1000\" \
+            max-size-time=60000000000  \
+            appsrc ! queue ! rtph264depay ! h264parse config-interval=-1 ! identity name=video_gate ! mux.video  \
+            appsrc ! queue ! rtpopusdepay ! opusparse ! identity name=audio_gate ! mux.audio_%u"
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_segments() {
+        let source = || Chain::new().element(ElementSpec::new("appsrc"));
+        let without_limit = RecordOptions::new().segments(SegmentOptions::new());
+        let with_limit = RecordOptions::new().segments(SegmentOptions::new().max_bytes(1 << 20));
+        let zero_duration =
+            RecordOptions::new().segments(SegmentOptions::new().max_duration(Duration::ZERO));
+        let zero_bytes = RecordOptions::new().segments(
+            SegmentOptions::new()
+                .max_duration(Duration::from_secs(60))
+                .max_bytes(0),
+        );
+
+        assert!(recording_pipeline("rec-%d.mp4", &without_limit, source(), None).is_err());
+        assert!(recording_pipeline("rec-%d.mp4", &zero_duration, source(), None).is_err());
+        assert!(recording_pipeline("rec-%d.mp4", &zero_bytes, source(), None).is_err());
+        assert!(recording_pipeline("rec.mp4", &with_limit, source(), None).is_err());
+        assert!(recording_pipeline("rec-%03d-%d.mp4", &with_limit, source(), None).is_err());
+        assert!(recording_pipeline("rec-%s.mp4", &with_limit, source(), None).is_err());
+        assert!(recording_pipeline("rec-100%%-%03d.mp4", &with_limit, source(), None).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_containers_not_fitting_the_codecs() {
+        let source = || Chain::new().element(ElementSpec::new("udpsrc"));
+        let vp8_in_ts = RecordOptions::new()
+            .video_codec(VideoCodec::Vp8)
+            .container(Container::MpegTs);
+        let pcma_in_webm = RecordOptions::new()
+            .video_codec(VideoCodec::Vp9)
+            .audio_codec(AudioCodec::Pcma)
+            .container(Container::WebM);
+
+        assert!(matches!(
+            recording_pipeline("video.ts", &vp8_in_ts, source(), None),
+            Err(MediaPipelineError::RecordOptionsError(_))
+        ));
+        assert!(matches!(
+            recording_pipeline("video.webm", &pcma_in_webm, source(), Some(source())),
+            Err(MediaPipelineError::RecordOptionsError(_))
+        ));
+        assert!(recording_pipeline("video.webm", &pcma_in_webm, source(), None).is_ok());
+    }
+
+    #[test]
+    fn it_checks_for_av1_plugins_up_front() {
+        let source = || Chain::new().element(ElementSpec::new("udpsrc"));
+        let av1 = RecordOptions::new().video_codec(VideoCodec::Av1);
+
+        // the standard plugin packages do not have the AV1 RTP elements
+        match recording_pipeline("video.mkv", &av1, source(), None) {
+            Ok(_) => assert!(gstreamer::ElementFactory::find("rtpav1depay").is_some()),
+            Err(MediaPipelineError::MissingElementError(message)) => {
+                assert!(message.contains("gst-plugins-rs"))
+            }
+            Err(error) => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn it_rejects_unknown_video_formats() {
+        let missing_rtpmap = SessionDescription::parse("v=0\nm=video 5000 RTP/AVP 102\n").unwrap();
+        let unsupported = SessionDescription::parse(
+            "v=0\nm=video 5000 RTP/AVP 102\na=rtpmap:102 MP4V-ES/90000\n",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            RecordOptions::from_sdp(&missing_rtpmap),
+            Err(MediaPipelineError::SdpError(_))
+        ));
+        assert!(matches!(
+            RecordOptions::from_sdp(&unsupported),
+            Err(MediaPipelineError::UnsupportedCodecError(_))
+        ));
+    }
+}
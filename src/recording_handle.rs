@@ -0,0 +1,146 @@
+use crate::error::{MediaPipelineError, Result};
+use crate::main_loop::{main_loop, outcome, Command};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use gstreamer::Pipeline;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// A recording running in the background.  Dropping the handle stops the
+// recording and waits until the file is finalized.
+pub struct RecordingHandle {
+    commands: Sender<Command>,
+    events: Receiver<Command>,
+    running: Arc<AtomicBool>,
+
+    // runs the main loop of the recording
+    worker: Option<JoinHandle<Result<()>>>,
+
+    // passes the events of the main loop on, keeping the one ending the recording
+    forwarder: Option<JoinHandle<Option<Result<PathBuf>>>>,
+}
+
+impl RecordingHandle {
+    // Run the main loop of a built pipeline in a thread, the output is the
+    // path the pipeline writes to
+    pub(crate) fn spawn(pipeline: Pipeline, output: PathBuf) -> Self {
+        let (inbound_sender, inbound_receiver) = unbounded::<Command>();
+        let (outbound_sender, outbound_receiver) = unbounded::<Command>();
+        let (events_sender, events_receiver) = unbounded::<Command>();
+        let running = Arc::new(AtomicBool::new(true));
+        let worker_running = running.clone();
+
+        let worker = std::thread::spawn(move || {
+            let result = main_loop(pipeline, output, inbound_receiver, outbound_sender).map(|_| ());
+
+            worker_running.store(false, Ordering::SeqCst);
+            result
+        });
+
+        // ends once the main loop and its threads have dropped their senders
+        let forwarder = std::thread::spawn(move || {
+            let mut result = None;
+
+            for event in outbound_receiver.iter() {
+                if result.is_none() {
+                    result = outcome(&event);
+                }
+
+                // not an error, nobody may be listening
+                let _ = events_sender.send(event);
+            }
+
+            result
+        });
+
+        RecordingHandle {
+            commands: inbound_sender,
+            events: events_receiver,
+            running,
+            worker: Some(worker),
+            forwarder: Some(forwarder),
+        }
+    }
+
+    // Events of the recording, e.g. `Command::SegmentFinished` or `Command::Warning`
+    pub fn events(&self) -> &Receiver<Command> {
+        &self.events
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    // Stop recording and wait until the file is finalized, returning its path
+    pub fn stop(mut self) -> Result<PathBuf> {
+        self.finish()
+    }
+
+    // Sends commands to the main loop, for wrappers of the handle
+    #[cfg(feature = "async")]
+    pub(crate) fn commands(&self) -> Sender<Command> {
+        self.commands.clone()
+    }
+
+    // Wait until the recording ends by itself or through the commands
+    #[cfg(feature = "async")]
+    pub(crate) fn wait(mut self) -> Result<PathBuf> {
+        self.join()
+    }
+
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            log::info!("Recording has already ended");
+        }
+    }
+
+    fn finish(&mut self) -> Result<PathBuf> {
+        self.send(Command::Stop);
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<PathBuf> {
+        let recorded = match self.worker.take() {
+            Some(worker) => worker.join().unwrap_or_else(|_| {
+                Err(MediaPipelineError::StopError(
+                    "the recording thread panicked".to_owned(),
+                ))
+            }),
+            None => Ok(()),
+        };
+        let result = self
+            .forwarder
+            .take()
+            .and_then(|forwarder| forwarder.join().ok())
+            .flatten();
+
+        recorded?;
+
+        result.unwrap_or_else(|| {
+            Err(MediaPipelineError::StopError(
+                "the recording ended without finishing the file".to_owned(),
+            ))
+        })
+    }
+}
+
+impl Drop for RecordingHandle {
+    fn drop(&mut self) {
+        if self.worker.is_none() {
+            return;
+        }
+
+        if let Err(error) = self.finish() {
+            log::error!("Error stopping a dropped recording: {}", error);
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use crate::element;
+use crate::error::{MediaPipelineError, Result};
+use crate::pipeline_builder::{CapsSpec, Chain, ElementSpec};
+use crate::record::RecordOptions;
+use crate::sdp::RtpFormat;
+use crate::udp_source::UdpSourceConfig;
+use gstreamer::prelude::*;
+use gstreamer::{Element, Pipeline};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub(crate) const RTPBIN: &str = "rtpbin";
+
+// The rtpbin sessions of the received streams
+pub(crate) const VIDEO_SESSION: u32 = 0;
+pub(crate) const AUDIO_SESSION: u32 = 1;
+
+// Names of the RTP sources linked to the rtpbin once the pipeline is built,
+// for sessions with RTX
+const VIDEO_RTP_SOURCE: &str = "video_rtp_source";
+const AUDIO_RTP_SOURCE: &str = "audio_rtp_source";
+
+fn rtp_source_name(session: u32) -> &'static str {
+    match session {
+        VIDEO_SESSION => VIDEO_RTP_SOURCE,
+        _ => AUDIO_RTP_SOURCE,
+    }
+}
+
+// RTCP of an RTP session.  Sender reports arriving on the receive address
+// are used to synchronize the streams, receiver reports are sent back if an
+// address is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtcpOptions {
+    // bound or joined like the source of the stream
+    receive: UdpSourceConfig,
+
+    // host and port of the sender's RTCP
+    send_to: Option<(String, u16)>,
+
+    nack: bool,
+    rtx: Option<RtxOptions>,
+}
+
+// Retransmitted packets (RFC 4588), sent on the port of the stream they repair
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtxOptions {
+    payload_type: u8,
+
+    // retransmissions of other SSRCs are dropped, None to take any
+    ssrc: Option<u32>,
+}
+
+impl RtxOptions {
+    pub fn new(payload_type: u8) -> Self {
+        RtxOptions {
+            payload_type,
+            ssrc: None,
+        }
+    }
+
+    pub fn ssrc(mut self, ssrc: u32) -> Self {
+        self.ssrc = Some(ssrc);
+        self
+    }
+
+    // Whether a packet is a retransmission of another SSRC than the expected one
+    fn is_foreign(&self, packet: &[u8]) -> bool {
+        match self.ssrc {
+            Some(ssrc) if packet.len() >= 12 && packet[1] & 0x7f == self.payload_type => {
+                u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]) != ssrc
+            }
+            _ => false,
+        }
+    }
+}
+
+impl RtcpOptions {
+    pub fn new(receive: UdpSourceConfig) -> Self {
+        RtcpOptions {
+            receive,
+            send_to: None,
+            nack: false,
+            rtx: None,
+        }
+    }
+
+    // Send receiver reports to the sender
+    pub fn send_to(mut self, host: &str, port: u16) -> Self {
+        self.send_to = Some((host.to_owned(), port));
+        self
+    }
+
+    // Ask the sender for lost packets (RFC 4585 generic NACKs), which needs
+    // `send_to`.  The jitter buffer waits up to its latency for them, only
+    // the sessions with NACKs enabled ask for retransmissions.
+    pub fn nack(mut self, nack: bool) -> Self {
+        self.nack = nack;
+        self
+    }
+
+    // Receive the retransmissions NACKs ask for as an RTX stream, enables NACKs
+    pub fn rtx(mut self, rtx: RtxOptions) -> Self {
+        self.nack = true;
+        self.rtx = Some(rtx);
+        self
+    }
+
+    pub(crate) fn sends_reports(&self) -> bool {
+        self.send_to.is_some()
+    }
+
+    pub(crate) fn uses_nack(&self) -> bool {
+        self.nack
+    }
+
+    // Checked against the payload type of the stream of the session
+    pub(crate) fn validate(&self, payload_type: u8) -> Result<()> {
+        let invalid =
+            |message: &str| Err(MediaPipelineError::RecordOptionsError(message.to_owned()));
+
+        self.receive.validate()?;
+
+        if self.nack && self.send_to.is_none() {
+            return invalid("NACKs need an RTCP address to be sent to");
+        }
+
+        match &self.rtx {
+            Some(rtx) if rtx.payload_type > 127 => invalid("the RTX payload type is above 127"),
+            Some(rtx) if rtx.payload_type == payload_type => {
+                invalid("the RTX payload type is the one of the stream it repairs")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Reception statistics of a stream source (SSRC) of a session
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RtcpSourceStats {
+    // "video" or "audio"
+    pub media: String,
+    pub ssrc: u32,
+    pub packets_received: u64,
+
+    // cumulative, negative when duplicates were received
+    pub packets_lost: i64,
+
+    // interarrival jitter
+    pub jitter: Duration,
+
+    // of the last receiver report sent for the source, between 0 and 1
+    pub fraction_lost: f64,
+
+    // only known when the source reports on what it receives from us
+    pub round_trip: Option<Duration>,
+
+    pub have_sender_report: bool,
+}
+
+impl RtcpSourceStats {
+    fn from_structure(media: &str, structure: &gstreamer::StructureRef) -> Self {
+        let clock_rate = structure.get::<i32>("clock-rate").unwrap_or(0);
+        let jitter = structure.get::<u32>("jitter").unwrap_or(0);
+        let have_report_block = structure.get::<bool>("have-rb").unwrap_or(false);
+
+        RtcpSourceStats {
+            media: media.to_owned(),
+            ssrc: structure.get::<u32>("ssrc").unwrap_or(0),
+            packets_received: structure.get::<u64>("packets-received").unwrap_or(0),
+            packets_lost: structure.get::<i32>("packets-lost").unwrap_or(0).into(),
+            // in units of the clock rate
+            jitter: match clock_rate {
+                rate if rate > 0 => Duration::from_secs_f64(f64::from(jitter) / f64::from(rate)),
+                _ => Duration::default(),
+            },
+            // in 1/256
+            fraction_lost: f64::from(structure.get::<u32>("sent-rb-fractionlost").unwrap_or(0))
+                / 256.0,
+            // in 1/65536 seconds
+            round_trip: structure
+                .get::<u32>("rb-round-trip")
+                .ok()
+                .filter(|_| have_report_block)
+                .map(|round_trip| Duration::from_secs_f64(f64::from(round_trip) / 65536.0)),
+            have_sender_report: structure.get::<bool>("have-sr").unwrap_or(false),
+        }
+    }
+}
+
+// The RTP and RTCP of a session into (and out of) the rtpbin, and the chain
+// the received RTP leaves it on, to be continued with its depayloader.  For
+// SRTP, the received RTCP is decrypted by the decoder of the RTP source.
+pub(crate) fn session_chains(
+    session: u32,
+    rtp_source: Chain,
+    format: &RtpFormat,
+    rtcp: Option<&RtcpOptions>,
+    srtp_decoder: Option<&str>,
+) -> (Chain, Vec<Chain>) {
+    let pad = |name: &str| format!("{}_{}", name, session);
+
+    // the receiver of retransmissions is set up when the pad is requested,
+    // which has to wait until the pipeline is built
+    let rtp_source = match rtcp.and_then(|rtcp| rtcp.rtx.as_ref()) {
+        Some(_) => rtp_source.element(ElementSpec::new("identity").name(rtp_source_name(session))),
+        None => rtp_source.to_pad(RTPBIN, Some(&pad("recv_rtp_sink"))),
+    };
+    let mut chains = vec![rtp_source];
+
+    if let Some(rtcp) = rtcp {
+        let rtcp_source = Chain::new().element(rtcp.receive.element());
+
+        match srtp_decoder {
+            Some(decoder) => chains.extend(vec![
+                rtcp_source
+                    .caps(CapsSpec::new("application/x-srtcp"))
+                    .to_pad(decoder, Some("rtcp_sink")),
+                Chain::from_pad(decoder, Some("rtcp_src"))
+                    .element(ElementSpec::new("identity"))
+                    .to_pad(RTPBIN, Some(&pad("recv_rtcp_sink"))),
+            ]),
+            None => chains.push(
+                rtcp_source
+                    .caps(CapsSpec::new("application/x-rtcp"))
+                    .to_pad(RTPBIN, Some(&pad("recv_rtcp_sink"))),
+            ),
+        }
+
+        if let Some((host, port)) = &rtcp.send_to {
+            chains.push(
+                Chain::from_pad(RTPBIN, Some(&pad("send_rtcp_src"))).element(
+                    ElementSpec::new("udpsink")
+                        .property("host", host.as_str())
+                        .property("port", *port)
+                        .property("sync", false)
+                        .property("async", false),
+                ),
+            );
+        }
+    }
+
+    // the caps pick the stream of this session when the rtpbin adds its pad
+    let received = Chain::from_pad(RTPBIN, None).caps(format.caps());
+
+    (received, chains)
+}
+
+// Turns the RTX stream of a session back into the stream it repairs
+fn rtx_receiver(session: u32, rtx: &RtxOptions, payload_type: u8) -> Result<Element> {
+    let bin = gstreamer::Bin::new(None);
+    let receive = gstreamer::ElementFactory::make("rtprtxreceive", None)
+        .map_err(|_| MediaPipelineError::MissingElementError("rtprtxreceive".to_owned()))?;
+    let payload_types = gstreamer::Structure::builder("application/x-rtp-pt-map")
+        .field(&rtx.payload_type.to_string(), u32::from(payload_type))
+        .build();
+
+    receive.set_property("payload-type-map", &payload_types)?;
+    bin.add(&receive)?;
+
+    let sink = receive
+        .static_pad("sink")
+        .ok_or(MediaPipelineError::CreateElementError("rtprtxreceive"))?;
+    let src = receive
+        .static_pad("src")
+        .ok_or(MediaPipelineError::CreateElementError("rtprtxreceive"))?;
+
+    if rtx.ssrc.is_some() {
+        let rtx = rtx.clone();
+
+        sink.add_probe(gstreamer::PadProbeType::BUFFER, move |_, info| {
+            let foreign = match &info.data {
+                Some(gstreamer::PadProbeData::Buffer(buffer)) => buffer
+                    .map_readable()
+                    .map(|map| rtx.is_foreign(&map))
+                    .unwrap_or(false),
+                _ => false,
+            };
+
+            if foreign {
+                gstreamer::PadProbeReturn::Drop
+            } else {
+                gstreamer::PadProbeReturn::Ok
+            }
+        });
+    }
+
+    bin.add_pad(&gstreamer::GhostPad::with_target(
+        Some(&format!("sink_{}", session)),
+        &sink,
+    )?)?;
+    bin.add_pad(&gstreamer::GhostPad::with_target(
+        Some(&format!("src_{}", session)),
+        &src,
+    )?)?;
+
+    Ok(bin.upcast())
+}
+
+// The sessions whose jitter buffers send NACKs
+fn nack_sessions(options: &RecordOptions) -> Vec<u32> {
+    [
+        (VIDEO_SESSION, options.rtcp_options()),
+        (AUDIO_SESSION, options.audio_rtcp_options()),
+    ]
+    .iter()
+    .filter(|(_, rtcp)| matches!(rtcp, Some(rtcp) if rtcp.uses_nack()))
+    .map(|(session, _)| *session)
+    .collect()
+}
+
+// Have the jitter buffers of the sessions with NACKs ask for retransmissions.
+// The do-retransmission of the rtpbin would enable them in every session.
+fn request_retransmissions(pipeline: &Pipeline, options: &RecordOptions) -> Result<()> {
+    let sessions = nack_sessions(options);
+
+    if sessions.is_empty() {
+        return Ok(());
+    }
+
+    // a jitter buffer is made for each stream source of a session
+    element::<Element>(pipeline, RTPBIN)?.connect("new-jitterbuffer", false, move |values| {
+        let jitter_buffer = values.get(1).and_then(|value| value.get::<Element>().ok());
+        let session = values.get(2).and_then(|value| value.get::<u32>().ok());
+
+        if let (Some(jitter_buffer), Some(session)) = (jitter_buffer, session) {
+            if sessions.contains(&session) {
+                if let Err(error) = jitter_buffer.set_property("do-retransmission", true) {
+                    log::error!("No NACKs for session {}: {}", session, error);
+                }
+            }
+        }
+
+        None
+    })?;
+
+    Ok(())
+}
+
+// Give the sessions with RTX a receiver for it and link their RTP sources to
+// the rtpbin, once the pipeline is built.  Also enables the NACKs asking for
+// retransmissions.
+pub(crate) fn attach_retransmission(pipeline: &Pipeline, options: &RecordOptions) -> Result<()> {
+    request_retransmissions(pipeline, options)?;
+
+    let sessions = [
+        (
+            VIDEO_SESSION,
+            options.rtcp_options(),
+            Some(options.video_format()),
+        ),
+        (
+            AUDIO_SESSION,
+            options.audio_rtcp_options(),
+            options.audio_format(),
+        ),
+    ];
+    let receivers = sessions
+        .iter()
+        .filter_map(|(session, rtcp, format)| {
+            let rtx = rtcp.and_then(|rtcp| rtcp.rtx.clone())?;
+
+            Some((*session, (rtx, format.as_ref()?.payload_type)))
+        })
+        .filter(|(session, _)| pipeline.by_name(rtp_source_name(*session)).is_some())
+        .collect::<HashMap<_, _>>();
+
+    if receivers.is_empty() {
+        return Ok(());
+    }
+
+    let rtpbin = element::<Element>(pipeline, RTPBIN)?;
+    let linked = receivers.keys().copied().collect::<Vec<_>>();
+
+    rtpbin.connect("request-aux-receiver", false, move |values| {
+        let receiver = values
+            .get(1)
+            .and_then(|session| session.get::<u32>().ok())
+            .and_then(|session| {
+                let (rtx, payload_type) = receivers.get(&session)?;
+
+                rtx_receiver(session, rtx, *payload_type)
+                    .map_err(|error| {
+                        log::error!("No RTX receiver for session {}: {}", session, error)
+                    })
+                    .ok()
+            });
+
+        Some(receiver.to_value())
+    })?;
+
+    for session in linked {
+        element::<Element>(pipeline, rtp_source_name(session))?
+            .link_pads(
+                Some("src"),
+                &rtpbin,
+                Some(&format!("recv_rtp_sink_{}", session)),
+            )
+            .map_err(|error| MediaPipelineError::PipelineBuilderError(error.to_string()))?;
+    }
+
+    Ok(())
+}
+
+// The stats of the remote sources of the rtpbin sessions in a pipeline,
+// empty if it has no rtpbin
+pub(crate) fn source_stats(pipeline: &Pipeline) -> Vec<RtcpSourceStats> {
+    let rtpbin = match pipeline.by_name(RTPBIN) {
+        Some(rtpbin) => rtpbin,
+        None => return Vec::new(),
+    };
+    let session_stats = |session: u32| -> Option<gstreamer::Structure> {
+        rtpbin
+            .emit_by_name("get-internal-session", &[&session])
+            .ok()??
+            .get::<Option<glib::Object>>()
+            .ok()??
+            .property("stats")
+            .ok()?
+            .get::<gstreamer::Structure>()
+            .ok()
+    };
+    let mut stats = Vec::new();
+
+    for (media, session) in [("video", VIDEO_SESSION), ("audio", AUDIO_SESSION)].iter() {
+        let sources = match session_stats(*session)
+            .and_then(|stats| stats.get::<glib::ValueArray>("source-stats").ok())
+        {
+            Some(sources) => sources,
+            None => continue,
+        };
+
+        for source in sources.iter() {
+            let source = match source.get::<gstreamer::Structure>() {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+
+            // our own source only sends RTCP
+            if source.get::<bool>("internal").unwrap_or(false) {
+                continue;
+            }
+
+            stats.push(RtcpSourceStats::from_structure(media, &source));
+        }
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_validates_retransmission() {
+        let rtcp = RtcpOptions::new(UdpSourceConfig::on_port(5001));
+
+        assert!(rtcp.clone().nack(true).validate(96).is_err());
+        assert!(RtcpOptions::new(UdpSourceConfig::on_port(0))
+            .validate(96)
+            .is_err());
+        assert!(rtcp
+            .clone()
+            .send_to("10.0.0.1", 5005)
+            .rtx(RtxOptions::new(128))
+            .validate(96)
+            .is_err());
+
+        let rtcp = rtcp.send_to("10.0.0.1", 5005).rtx(RtxOptions::new(97));
+
+        assert!(rtcp.uses_nack());
+        assert!(rtcp.validate(96).is_ok());
+        assert!(rtcp.validate(97).is_err());
+    }
+
+    #[test]
+    fn it_sends_nacks_only_for_sessions_asking_for_them() {
+        let video = RtcpOptions::new(UdpSourceConfig::on_port(5001))
+            .send_to("10.0.0.1", 5005)
+            .nack(true);
+        let audio = RtcpOptions::new(UdpSourceConfig::on_port(5003)).send_to("10.0.0.1", 5007);
+        let options = RecordOptions::new().rtcp(video.clone());
+
+        assert_eq!(nack_sessions(&options), vec![VIDEO_SESSION]);
+        assert_eq!(
+            nack_sessions(&options.clone().audio_rtcp(audio)),
+            vec![VIDEO_SESSION]
+        );
+        assert_eq!(
+            nack_sessions(&options.audio_rtcp(video)),
+            vec![VIDEO_SESSION, AUDIO_SESSION]
+        );
+        assert!(nack_sessions(&RecordOptions::new()).is_empty());
+    }
+
+    #[test]
+    fn it_drops_foreign_retransmissions() {
+        let rtx = RtxOptions::new(97).ssrc(0x0102_0304);
+        let packet = |payload_type: u8, ssrc: [u8; 4]| {
+            [&[0x80, payload_type, 0, 1, 0, 0, 0, 0][..], &ssrc[..]].concat()
+        };
+
+        assert!(!rtx.is_foreign(&packet(97, [1, 2, 3, 4])));
+        assert!(rtx.is_foreign(&packet(97, [4, 3, 2, 1])));
+        assert!(!rtx.is_foreign(&packet(96, [4, 3, 2, 1])));
+        assert!(!RtxOptions::new(97).is_foreign(&packet(97, [4, 3, 2, 1])));
+    }
+
+    #[test]
+    fn it_reads_source_stats() {
+        gstreamer::init().unwrap();
+
+        let structure = gstreamer::Structure::builder("application/x-rtp-source-stats")
+            .field("ssrc", 1234u32)
+            .field("clock-rate", 90000i32)
+            .field("packets-received", 500u64)
+            .field("packets-lost", 5i32)
+            .field("jitter", 900u32)
+            .field("sent-rb-fractionlost", 64u32)
+            .field("have-rb", true)
+            .field("rb-round-trip", 6554u32)
+            .field("have-sr", true)
+            .build();
+        let stats = RtcpSourceStats::from_structure("video", &structure);
+
+        assert_eq!(stats.ssrc, 1234);
+        assert_eq!(stats.packets_received, 500);
+        assert_eq!(stats.packets_lost, 5);
+        assert_eq!(stats.jitter.as_micros(), 10_000);
+        assert_eq!(stats.fraction_lost, 0.25);
+        assert_eq!(stats.round_trip.unwrap().as_millis(), 100);
+        assert!(stats.have_sender_report);
+    }
+}
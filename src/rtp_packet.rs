@@ -0,0 +1,312 @@
+use crate::error::{MediaPipelineError, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+const HEADER_LENGTH: usize = 12;
+
+// Profiles of RFC 8285 header extensions
+const ONE_BYTE_PROFILE: u16 = 0xbede;
+const TWO_BYTE_PROFILE_MASK: u16 = 0xfff0;
+const TWO_BYTE_PROFILE: u16 = 0x1000;
+
+// An RTP packet (RFC 3550).  Padding is removed when parsing and not written
+// when serializing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpPacket {
+    pub version: u8,
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub csrcs: Vec<u32>,
+    pub extensions: Option<RtpExtensions>,
+    pub payload: Bytes,
+}
+
+// The header extension of a packet, its data without the profile and length words
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpExtensions {
+    pub profile: u16,
+    pub data: Bytes,
+}
+
+// A single extension of the one-byte or two-byte header formats (RFC 8285)
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpExtension {
+    pub id: u8,
+    pub data: Bytes,
+}
+
+impl RtpPacket {
+    // Parse a packet, sharing the payload with `packet`
+    pub fn parse(packet: Bytes) -> Result<Self> {
+        if packet.len() < HEADER_LENGTH {
+            return Err(rtp_error(format!("{} bytes are too short", packet.len())));
+        }
+
+        let version = packet[0] >> 6;
+
+        if version != 2 {
+            return Err(rtp_error(format!("unsupported version {}", version)));
+        }
+
+        let padding = packet[0] & 0x20 != 0;
+        let has_extension = packet[0] & 0x10 != 0;
+        let csrc_count = usize::from(packet[0] & 0x0f);
+        let mut offset = HEADER_LENGTH + 4 * csrc_count;
+        let mut end = packet.len();
+
+        if offset > end {
+            return Err(rtp_error(format!("too short for {} CSRCs", csrc_count)));
+        }
+
+        let csrcs = (0..csrc_count)
+            .map(|index| read_u32(&packet, HEADER_LENGTH + 4 * index))
+            .collect();
+
+        let extensions = if has_extension {
+            if offset + 4 > end {
+                return Err(rtp_error("too short for the header extension".to_owned()));
+            }
+
+            let profile = read_u16(&packet, offset);
+            let length = 4 * usize::from(read_u16(&packet, offset + 2));
+            let start = offset + 4;
+
+            offset = start + length;
+
+            if offset > end {
+                return Err(rtp_error(format!(
+                    "too short for a header extension of {} bytes",
+                    length
+                )));
+            }
+
+            Some(RtpExtensions {
+                profile,
+                data: packet.slice(start..offset),
+            })
+        } else {
+            None
+        };
+
+        if padding {
+            let padding_length = usize::from(packet[end - 1]);
+
+            if padding_length == 0 || offset + padding_length > end {
+                return Err(rtp_error(format!("invalid padding of {}", padding_length)));
+            }
+
+            end -= padding_length;
+        }
+
+        Ok(RtpPacket {
+            version,
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: read_u16(&packet, 2),
+            timestamp: read_u32(&packet, 4),
+            ssrc: read_u32(&packet, 8),
+            csrcs,
+            extensions,
+            payload: packet.slice(offset..end),
+        })
+    }
+
+    // Parse a packet from a slice, copying it
+    pub fn from_slice(packet: &[u8]) -> Result<Self> {
+        RtpPacket::parse(Bytes::copy_from_slice(packet))
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        if self.version > 3 {
+            return Err(rtp_error(format!("version {} does not fit", self.version)));
+        }
+
+        if self.payload_type > 0x7f {
+            return Err(rtp_error(format!(
+                "payload type {} does not fit",
+                self.payload_type
+            )));
+        }
+
+        if self.csrcs.len() > 15 {
+            return Err(rtp_error(format!("{} CSRCs do not fit", self.csrcs.len())));
+        }
+
+        let mut packet = BytesMut::with_capacity(
+            HEADER_LENGTH
+                + 4 * self.csrcs.len()
+                + self.extensions.as_ref().map_or(0, |e| 4 + e.data.len())
+                + self.payload.len(),
+        );
+
+        packet.put_u8(
+            self.version << 6 | u8::from(self.extensions.is_some()) << 4 | self.csrcs.len() as u8,
+        );
+        packet.put_u8(u8::from(self.marker) << 7 | self.payload_type);
+        packet.put_u16(self.sequence);
+        packet.put_u32(self.timestamp);
+        packet.put_u32(self.ssrc);
+
+        for csrc in &self.csrcs {
+            packet.put_u32(*csrc);
+        }
+
+        if let Some(extensions) = &self.extensions {
+            let words = extensions.data.len() / 4;
+
+            if extensions.data.len() % 4 != 0 || words > usize::from(u16::MAX) {
+                return Err(rtp_error(format!(
+                    "a header extension of {} bytes does not fit",
+                    extensions.data.len()
+                )));
+            }
+
+            packet.put_u16(extensions.profile);
+            packet.put_u16(words as u16);
+            packet.put_slice(&extensions.data);
+        }
+
+        packet.put_slice(&self.payload);
+
+        Ok(packet.freeze())
+    }
+}
+
+impl RtpExtensions {
+    // The extensions of the one-byte or two-byte header formats (RFC 8285)
+    pub fn elements(&self) -> Result<Vec<RtpExtension>> {
+        let one_byte = if self.profile == ONE_BYTE_PROFILE {
+            true
+        } else if self.profile & TWO_BYTE_PROFILE_MASK == TWO_BYTE_PROFILE {
+            false
+        } else {
+            return Err(rtp_error(format!(
+                "header extension profile {:#06x} has no elements",
+                self.profile
+            )));
+        };
+
+        let data = &self.data;
+        let mut elements = Vec::new();
+        let mut offset = 0;
+
+        while offset < data.len() {
+            // padding between elements
+            if data[offset] == 0 {
+                offset += 1;
+                continue;
+            }
+
+            let (id, length, start) = if one_byte {
+                let id = data[offset] >> 4;
+
+                // 15 is reserved and ends the extensions
+                if id == 15 {
+                    break;
+                }
+
+                (id, usize::from(data[offset] & 0x0f) + 1, offset + 1)
+            } else {
+                match data.get(offset + 1) {
+                    Some(length) => (data[offset], usize::from(*length), offset + 2),
+                    None => return Err(rtp_error("truncated header extension".to_owned())),
+                }
+            };
+
+            if start + length > data.len() {
+                return Err(rtp_error(format!("truncated header extension {}", id)));
+            }
+
+            elements.push(RtpExtension {
+                id,
+                data: data.slice(start..start + length),
+            });
+            offset = start + length;
+        }
+
+        Ok(elements)
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([packet[offset], packet[offset + 1]])
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        packet[offset],
+        packet[offset + 1],
+        packet[offset + 2],
+        packet[offset + 3],
+    ])
+}
+
+fn rtp_error(message: String) -> MediaPipelineError {
+    MediaPipelineError::RtpError(message)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_parses_rtp_packets() {
+        let packet = [
+            // header with padding, extension and one CSRC
+            &[
+                0xb1, 0xe0, 0x12, 0x34, 0, 0, 0x0b, 0xb8, 0xde, 0xad, 0xbe, 0xef,
+            ][..],
+            &[0, 0, 0, 7],                            // CSRC
+            &[0xbe, 0xde, 0, 1, 0x11, 0xaa, 0xbb, 0], // one-byte extensions
+            &[1, 2, 3],                               // payload
+            &[0, 2],                                  // padding
+        ]
+        .concat();
+        let parsed = RtpPacket::from_slice(&packet).unwrap();
+
+        assert_eq!(parsed.version, 2);
+        assert!(parsed.marker);
+        assert_eq!(parsed.payload_type, 96);
+        assert_eq!(parsed.sequence, 0x1234);
+        assert_eq!(parsed.timestamp, 3000);
+        assert_eq!(parsed.ssrc, 0xdeadbeef);
+        assert_eq!(parsed.csrcs, vec![7]);
+        assert_eq!(&parsed.payload[..], &[1, 2, 3]);
+        assert_eq!(
+            parsed.extensions.as_ref().unwrap().elements().unwrap(),
+            vec![RtpExtension {
+                id: 1,
+                data: Bytes::from_static(&[0xaa, 0xbb]),
+            }]
+        );
+
+        // serializing drops the padding
+        let serialized = parsed.to_bytes().unwrap();
+
+        assert_eq!(serialized[0], 0x91);
+        assert_eq!(&serialized[1..], &packet[1..packet.len() - 2]);
+        assert_eq!(RtpPacket::parse(serialized).unwrap(), parsed);
+    }
+
+    #[test]
+    fn it_rejects_invalid_packets() {
+        let header = [0x80, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+
+        assert!(RtpPacket::from_slice(&header[..11]).is_err());
+        assert!(RtpPacket::from_slice(&[&[0x40], &header[1..]].concat()).is_err());
+        assert!(RtpPacket::from_slice(&[&[0x82], &header[1..]].concat()).is_err());
+        assert!(
+            RtpPacket::from_slice(&[&[0x90], &header[1..], &[0xbe, 0xde, 0, 1]].concat()).is_err()
+        );
+        assert!(RtpPacket::from_slice(&[&[0xa0], &header[1..], &[0, 0, 9]].concat()).is_err());
+        assert!(RtpPacket::from_slice(&header).unwrap().payload.is_empty());
+
+        let mut packet = RtpPacket::from_slice(&header).unwrap();
+
+        packet.payload_type = 128;
+        assert!(packet.to_bytes().is_err());
+    }
+}
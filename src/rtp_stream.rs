@@ -1,96 +1,101 @@
+use crate::appsink::{
+    copy_bytes, set_sample_callbacks, share_bytes, AppsinkOptions, BufferReceiver, BufferSender,
+    Convert, DroppedBuffers,
+};
+use crate::element;
 use crate::error::Result;
 use crate::main_loop::main_loop_simple;
-use crate::{create_pipeline, element};
-use byte_slice_cast::*;
-use bytes::BytesMut;
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use gstreamer::element_error;
-use gstreamer_app::{AppSink, AppSinkCallbacks};
-
-fn pipeline(sender: Sender<BytesMut>) -> Result<gstreamer::Pipeline> {
-    let launch = "videotestsrc ! video/x-raw,format=I420,framerate=30/1,width=1280,height=720 ! x264enc tune=zerolatency ! rtph264pay ! appsink name=sink";
-    let pipeline = create_pipeline(launch)?;
+use crate::pipeline_builder::PipelineBuilder;
+use crate::rtp_packet::RtpPacket;
+use crate::rtp_udp_server::{source_chain, TestSourceConfig};
+use bytes::{Bytes, BytesMut};
+use crossbeam_channel::{Receiver, Sender};
+use gstreamer::{FlowError, Sample};
+use gstreamer_app::AppSink;
+
+fn pipeline<T: Send + 'static>(
+    sender: BufferSender<T>,
+    options: &AppsinkOptions,
+    convert: Convert<T>,
+) -> Result<gstreamer::Pipeline> {
+    let pipeline = PipelineBuilder::new()
+        .chain(source_chain(&TestSourceConfig::default()).element(options.appsink("sink")))
+        .build()?;
     let appsink = element::<AppSink>(&pipeline, "sink")?;
 
-    // Getting data out of the appsink is done by setting callbacks on it.
-    // The appsink will then call those handlers, as soon as data is available.
-    appsink.set_callbacks(
-        AppSinkCallbacks::builder()
-            // Add a handler to the "new-sample" signal.
-            .new_sample(move |appsink| {
-                // Pull the sample in question out of the appsink's buffer.
-                let sample = appsink
-                    .pull_sample()
-                    .map_err(|_| gstreamer::FlowError::Eos)?;
-
-                let buffer = sample.buffer().ok_or_else(|| {
-                    element_error!(
-                        appsink,
-                        gstreamer::ResourceError::Failed,
-                        ("Failed to get buffer from appsink")
-                    );
-
-                    gstreamer::FlowError::Error
-                })?;
-
-                // At this point, buffer is only a reference to an existing memory region somewhere.
-                // When we want to access its content, we have to map it while requesting the required
-                // mode of access (read, read/write).
-                // This type of abstraction is necessary, because the buffer in question might not be
-                // on the machine's main memory itself, but rather in the GPU's memory.
-                // So mapping the buffer makes the underlying memory region accessible to us.
-                // See: https://gstreamer.freedesktop.org/documentation/plugin-development/advanced/allocation.html
-                let map = buffer.map_readable().map_err(|_| {
-                    element_error!(
-                        appsink,
-                        gstreamer::ResourceError::Failed,
-                        ("Failed to map buffer readable")
-                    );
-
-                    gstreamer::FlowError::Error
-                })?;
-
-                // We know what format the data in the memory region has, since we requested
-                // it by setting the appsink's caps. So what we do here is interpret the
-                // memory region we mapped as an array of u8 packets.
-                let samples = map.as_slice_of::<u8>().map_err(|_| {
-                    element_error!(
-                        appsink,
-                        gstreamer::ResourceError::Failed,
-                        ("Failed to interprete buffer as S16 PCM")
-                    );
-
-                    gstreamer::FlowError::Error
-                })?;
-
-                // not an error, just the receiver is no longer around
-                if sender
-                    .send(BytesMut::from(samples.to_owned().as_byte_slice()))
-                    .is_err()
-                {
-                    log::info!("Receiver not able to receive bytes from the rtp stream");
-                };
-
-                Ok(gstreamer::FlowSuccess::Ok)
-            })
-            .build(),
-    );
+    set_sample_callbacks(&appsink, convert, move |data| {
+        let sent = sender.send(data);
+
+        // not an error, just the receiver is no longer around
+        if !sent {
+            log::info!("Receiver not able to receive bytes from the rtp stream, stopping it");
+        }
+
+        sent
+    });
 
     Ok(pipeline)
 }
 
-pub fn start() -> (Sender<BytesMut>, Receiver<BytesMut>) {
-    let (send, recv) = unbounded::<BytesMut>();
-    let sender_outbound = send.clone();
-
-    std::thread::spawn(|| {
-        match pipeline(send).and_then(main_loop_simple) {
+fn spawn<T: Send + 'static>(sender: BufferSender<T>, options: AppsinkOptions, convert: Convert<T>) {
+    std::thread::spawn(move || {
+        match pipeline(sender, &options, convert).and_then(main_loop_simple) {
             Ok(r) => r,
             Err(e) => log::error!("Error! {}", e),
         };
     });
+}
+
+pub fn start() -> (Sender<BytesMut>, Receiver<BytesMut>) {
+    let options = AppsinkOptions::new().unbounded();
+    let (send, recv) = options.channel();
+    let sender_outbound = send.sender();
 
-    (sender_outbound, recv)
+    spawn(send, options, copy_bytes);
+
+    (sender_outbound, recv.into_receiver())
+}
+
+// Stream with the channel and appsink set up by the options, also returning
+// the count of buffers the drop policy dropped
+pub fn start_with_options(options: AppsinkOptions) -> (BufferReceiver<BytesMut>, DroppedBuffers) {
+    let (send, recv) = options.channel();
+    let dropped = send.dropped();
+
+    spawn(send, options, copy_bytes);
+
+    (recv, dropped)
+}
+
+// Like `start_with_options`, with bytes sharing the memory of the GStreamer
+// buffers instead of copies of it
+pub fn start_bytes(options: AppsinkOptions) -> (BufferReceiver<Bytes>, DroppedBuffers) {
+    let (send, recv) = options.channel();
+    let dropped = send.dropped();
+
+    spawn(send, options, share_bytes);
+
+    (recv, dropped)
+}
+
+// Like `start_bytes`, with each buffer parsed as an RTP packet.  Buffers that
+// are not valid RTP are passed on as errors.
+pub fn start_parsed(
+    options: AppsinkOptions,
+) -> (BufferReceiver<Result<RtpPacket>>, DroppedBuffers) {
+    let (send, recv) = options.channel();
+    let dropped = send.dropped();
+
+    spawn(send, options, parse_packet);
+
+    (recv, dropped)
+}
+
+fn parse_packet(
+    appsink: &AppSink,
+    sample: Sample,
+) -> std::result::Result<Result<RtpPacket>, FlowError> {
+    share_bytes(appsink, sample).map(RtpPacket::parse)
 }
 
 #[cfg(test)]
@@ -114,4 +119,52 @@ mod tests {
 
         assert_eq!(count, max);
     }
+
+    #[test]
+    fn it_drops_rtp_the_consumer_cannot_take() {
+        let options = AppsinkOptions::new()
+            .capacity(1)
+            .policy(crate::appsink::DropPolicy::DropNewest);
+        let (rx, dropped) = start_with_options(options);
+
+        // not receiving lets the channel fill up
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        assert!(rx.recv().is_ok());
+        assert!(dropped.count() > 0);
+    }
+
+    #[test]
+    fn it_streams_rtp_without_copies() {
+        let (rx, _) = start_bytes(AppsinkOptions::new());
+        let packet = rx.recv().unwrap();
+
+        // an RTP version 2 header
+        assert_eq!(packet[0] >> 6, 2);
+    }
+
+    #[test]
+    fn it_streams_parsed_rtp() {
+        let (rx, _) = start_parsed(AppsinkOptions::new());
+        let first = rx.recv().unwrap().unwrap();
+        let second = rx.recv().unwrap().unwrap();
+
+        assert_eq!(first.version, 2);
+        assert_eq!(second.ssrc, first.ssrc);
+        assert_eq!(second.sequence, first.sequence.wrapping_add(1));
+    }
+
+    #[test]
+    fn it_stops_once_the_consumer_is_gone() {
+        let options = AppsinkOptions::new()
+            .capacity(1)
+            .policy(crate::appsink::DropPolicy::DropOldest);
+        let (sender, receiver) = options.channel();
+        let pipeline = pipeline(sender, &options, copy_bytes).unwrap();
+
+        drop(receiver);
+
+        // returns once the pipeline has ended
+        assert!(main_loop_simple(pipeline).is_ok());
+    }
 }
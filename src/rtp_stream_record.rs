@@ -1,79 +1,132 @@
-use crate::error::Result;
+use crate::element;
+use crate::error::{MediaPipelineError, Result};
+use crate::fec::{attach_fec, jitter_buffer_chain, FecOptions};
 use crate::main_loop::{main_loop, Command};
-use crate::{create_pipeline, element};
+use crate::pipeline_builder::{Chain, ElementSpec};
+use crate::record::{recording_pipeline, RecordOptions};
+use crate::recording_handle::RecordingHandle;
+use crate::sdp::{RtpFormat, SessionDescription};
 use bytes::BytesMut;
 use crossbeam_channel::{Receiver, Sender};
 use glib::MainLoop;
 use gstreamer::Pipeline;
 use gstreamer_app::AppSrc;
+use std::time::Instant;
 
-fn pipeline(filename: &str, receiver: Receiver<BytesMut>) -> Result<Pipeline> {
-    // TODO: handle different formats
-    // TODO: research sdpdemux for handling flexible formats
-    let launch = format!(
-        "appsrc name=src  \
-        ! application/x-rtp, media=(string)video, clock-rate=(int)90000, encoding-name=(string)H264, payload=(int)96
-        ! queue \
-            ! rtph264depay name=pay0 \
-            ! h264parse config-interval=-1 \
-            ! mp4mux \
-            ! filesink location={}",
-        filename
-    );
+// Turns RTP timestamps into buffer timestamps.  The first packet is placed at
+// its arrival time since an epoch shared by all streams of a recording, later
+// packets by their RTP timestamp distance to it.
+struct RtpClock {
+    clock_rate: i128,
+    epoch: Instant,
 
-    let pipeline = create_pipeline(&launch)?;
-    let appsrc = element::<AppSrc>(&pipeline, "src")?;
-
-    // I got these caps by running the stream creation on the command line and
-    // used the last caps output.
-    //
-    // run this:
-    //
-    // gst-launch-1.0 -v videotestsrc ! video/x-raw,format=I420,framerate=30/1,width=1280,height=720 ! x264enc tune=zerolatency ! rtph264pay ! autovideosink
-    //
-    // then this is output:
-    //
-    // Got context from element 'autovideosink0': gst.gl.GLDisplay=context, gst.gl.GLDisplay=(GstGLDisplay)"\(GstGLDisplayCocoa\)\ gldisplaycocoa0";
-    // /GstPipeline:pipeline0/GstVideoTestSrc:videotestsrc0.GstPad:src: caps = video/x-raw, format=(string)I420, width=(int)1280, height=(int)720, framerate=(fraction)30/1, multiview-mode=(string)mono, pixel-aspect-ratio=(fraction)1/1, interlace-mode=(string)progressive
-    // /GstPipeline:pipeline0/GstCapsFilter:capsfilter0.GstPad:src: caps =
-    // Redistribute latency...
-    // /GstPipeline:pipeline0/GstX264Enc:x264enc0.GstPad:sink: caps = video/x-raw, format=(string)I420, width=(int)1280, height=(int)720, framerate=(fraction)30/1, multiview-mode=(string)mono, pixel-aspect-ratio=(fraction)1/1, interlace-mode=(string)progressive
-    // /GstPipeline:pipeline0/GstCapsFilter:capsfilter0.GstPad:sink: caps = video/x-raw, format=(string)I420, width=(int)1280, height=(int)720, framerate=(fraction)30/1, multiview-mode=(string)mono, pixel-aspect-ratio=(fraction)1/1, interlace-mode=(string)progressive
-    // /GstPipeline:pipeline0/GstX264Enc:x264enc0.GstPad:src: caps = video/x-h264, codec_data=(buffer)0142c01fffe1001b6742c01fd9005005bb016a02020280000003008000001e478c192401000468cb8cb2, stream-format=(string)avc, alignment=(string)au, level=(string)3.1, profile=(string)constrained-baseline, width=(int)1280, height=(int)720, pixel-aspect-ratio=(fraction)1/1, framerate=(fraction)30/1, interlace-mode=(string)progressive, colorimetry=(string)bt709, chroma-site=(string)mpeg2, multiview-mode=(string)mono, multiview-flags=(GstVideoMultiviewFlagsSet)0:ffffffff:/right-view-first/left-flipped/left-flopped/right-flipped/right-flopped/half-aspect/mixed-mono
-    //
-    // TODO: figure out a way to get caps from sdp
-
-    let caps = gstreamer::Caps::new_simple(
-        "application/x-rtp",
-        &[
-            ("format", &"H264"),
-            ("media", &"video"),
-            ("clock-rate", &90000),
-            ("payload", &96),
-            ("chroma-site", &"mpeg2"),
-            ("colorimetry", &"bt709"),
-        ],
-    );
+    // extended RTP timestamp and nanoseconds since the epoch of the first packet
+    first: Option<(i64, u64)>,
+
+    // last RTP timestamp and its extended value
+    last: Option<(u32, i64)>,
+}
+
+impl RtpClock {
+    fn new(clock_rate: u32, epoch: Instant) -> Self {
+        RtpClock {
+            clock_rate: i128::from(clock_rate.max(1)),
+            epoch,
+            first: None,
+            last: None,
+        }
+    }
+
+    // Extend a 32 bit timestamp past wraparounds, treating differences of less
+    // than half the range as steps forward or (for reordered packets) backward
+    fn extend(&mut self, timestamp: u32) -> i64 {
+        let extended = match self.last {
+            Some((last, extended)) => extended + i64::from(timestamp.wrapping_sub(last) as i32),
+            None => i64::from(timestamp),
+        };
+
+        self.last = Some((timestamp, extended));
+        extended
+    }
+
+    // Presentation time in nanoseconds of a packet
+    fn pts(&mut self, timestamp: u32, arrival: Instant) -> u64 {
+        let extended = self.extend(timestamp);
+        let epoch = self.epoch;
+        let (first, base) = *self.first.get_or_insert_with(|| {
+            let since_epoch = arrival.saturating_duration_since(epoch).as_nanos();
 
-    appsrc.set_caps(Some(&caps));
-    appsrc.set_format(gstreamer::Format::Time);
+            (extended, since_epoch as u64)
+        });
+        let offset = i128::from(extended - first) * 1_000_000_000 / self.clock_rate;
 
-    // write to the buffer in a separate thread to the appsrc in demand
-    // it is not required to use the need_data callback
+        (i128::from(base) + offset).max(0) as u64
+    }
+}
+
+// The timestamp field of an RTP header
+fn rtp_timestamp(packet: &[u8]) -> Option<u32> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+
+    Some(u32::from_be_bytes([
+        packet[4], packet[5], packet[6], packet[7],
+    ]))
+}
+
+// An appsrc producing RTP in a format
+fn source_chain(name: &str, format: &RtpFormat) -> Chain {
+    Chain::new().element(
+        ElementSpec::new("appsrc")
+            .name(name)
+            .property("caps", format.caps())
+            .property("format", "time")
+            .property("is-live", true),
+    )
+}
+
+// The appsrc of a stream, followed by a jitter buffer reporting the packets
+// lost on the way to FEC recovery if the stream is protected
+fn protected_source_chain(
+    name: &str,
+    media: &str,
+    format: &RtpFormat,
+    options: &RecordOptions,
+    fec: Option<&FecOptions>,
+) -> Result<Chain> {
+    match fec {
+        Some(fec) => jitter_buffer_chain(
+            media,
+            source_chain(name, format),
+            options.jitter_buffer_options(),
+            Some(fec),
+        ),
+        None => Ok(source_chain(name, format)),
+    }
+}
+
+// Write to the appsrc in a separate thread as packets arrive,
+// it is not required to use the need_data callback
+fn feed(appsrc: AppSrc, receiver: Receiver<BytesMut>, mut clock: RtpClock) {
     std::thread::spawn(move || {
-        let mut count = 1;
         while let Ok(bytes) = receiver.recv() {
             log::trace!("received bytes: {:?}", bytes);
 
+            let timestamp = match rtp_timestamp(&bytes) {
+                Some(timestamp) => timestamp,
+                None => {
+                    log::warn!("Dropping {} bytes that are not an RTP packet", bytes.len());
+                    continue;
+                }
+            };
+            let pts = clock.pts(timestamp, Instant::now());
             let mut buffer = gstreamer::Buffer::from_slice(bytes);
-            // For each frame we produce, we set the timestamp when it should be displayed
-            // (pts = presentation time stamp)
-            // The autovideosink will use this information to display the frame at the right time.
-            // TODO: figure out a way to make the below more accurate (2500 makes a single use cas work)
-            let pts = count * 2500 * gstreamer::ClockTime::USECOND;
 
+            // For each packet we set the timestamp when it should be presented
+            // (pts = presentation time stamp), so the recording plays back in real time
             if let Some(mut_buffer) = buffer.get_mut() {
-                mut_buffer.set_pts(pts);
+                mut_buffer.set_pts(gstreamer::ClockTime::from_nseconds(pts));
             }
 
             // not an error, just the buffer is flushing
@@ -82,10 +135,61 @@ fn pipeline(filename: &str, receiver: Receiver<BytesMut>) -> Result<Pipeline> {
                 let _ = appsrc.end_of_stream();
                 break;
             }
-
-            count += 1;
         }
     });
+}
+
+fn pipeline(
+    filename: &str,
+    video_receiver: Receiver<BytesMut>,
+    audio_receiver: Option<Receiver<BytesMut>>,
+    options: &RecordOptions,
+) -> Result<Pipeline> {
+    let epoch = Instant::now();
+    let video_format = options.video_format();
+    let audio_format = match audio_receiver {
+        Some(_) => Some(options.required_audio_format()?),
+        None => None,
+    };
+    let audio_source = match &audio_format {
+        Some(format) => Some(protected_source_chain(
+            "audio_src",
+            "audio",
+            format,
+            options,
+            options.audio_fec_options(),
+        )?),
+        None => None,
+    };
+    let pipeline = recording_pipeline(
+        filename,
+        options,
+        protected_source_chain(
+            "src",
+            "video",
+            &video_format,
+            options,
+            options.fec_options(),
+        )?,
+        audio_source,
+    )?
+    .build()?;
+
+    attach_fec(&pipeline, options)?;
+
+    feed(
+        element::<AppSrc>(&pipeline, "src")?,
+        video_receiver,
+        RtpClock::new(video_format.clock_rate, epoch),
+    );
+
+    if let (Some(audio_receiver), Some(audio_format)) = (audio_receiver, audio_format) {
+        feed(
+            element::<AppSrc>(&pipeline, "audio_src")?,
+            audio_receiver,
+            RtpClock::new(audio_format.clock_rate, epoch),
+        );
+    }
 
     Ok(pipeline)
 }
@@ -95,11 +199,103 @@ pub fn record(
     receiver: Receiver<BytesMut>,
     inbound_receiver: Receiver<Command>,
     outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    record_with_options(
+        filename,
+        receiver,
+        RecordOptions::default(),
+        inbound_receiver,
+        outbound_sender,
+    )
+}
+
+// Record a stream described by an SDP document.  An audio section can only be
+// recorded with its own packets, so it is refused here, see
+// `record_with_sdp_and_audio`.
+pub fn record_with_sdp(
+    filename: &str,
+    sdp: &SessionDescription,
+    receiver: Receiver<BytesMut>,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    if sdp.media("audio").is_some() {
+        return Err(MediaPipelineError::SdpError(
+            "the audio section would not be recorded, use record_with_sdp_and_audio".to_owned(),
+        ));
+    }
+
+    record_with_options(
+        filename,
+        receiver,
+        RecordOptions::from_sdp(sdp)?,
+        inbound_receiver,
+        outbound_sender,
+    )
+}
+
+// Record the video and audio streams described by an SDP document into one
+// file, failing if the audio codec cannot be recorded
+pub fn record_with_sdp_and_audio(
+    filename: &str,
+    sdp: &SessionDescription,
+    video_receiver: Receiver<BytesMut>,
+    audio_receiver: Receiver<BytesMut>,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    record_with_audio(
+        filename,
+        video_receiver,
+        audio_receiver,
+        RecordOptions::from_sdp(sdp)?,
+        inbound_receiver,
+        outbound_sender,
+    )
+}
+
+pub fn record_with_options(
+    filename: &str,
+    receiver: Receiver<BytesMut>,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
 ) -> Result<MainLoop> {
     log::info!("Starting to record {}", filename);
 
-    pipeline(filename, receiver)
-        .and_then(|pipeline| main_loop(pipeline, inbound_receiver, outbound_sender))
+    pipeline(filename, receiver, None, &options).and_then(|pipeline| {
+        main_loop(pipeline, filename.into(), inbound_receiver, outbound_sender)
+    })
+}
+
+// Record a video and an audio stream into one file, the audio codec
+// has to be set in the options
+pub fn record_with_audio(
+    filename: &str,
+    video_receiver: Receiver<BytesMut>,
+    audio_receiver: Receiver<BytesMut>,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    log::info!("Starting to record {} with audio", filename);
+
+    pipeline(filename, video_receiver, Some(audio_receiver), &options).and_then(|pipeline| {
+        main_loop(pipeline, filename.into(), inbound_receiver, outbound_sender)
+    })
+}
+
+// Start recording in the background, returning once the pipeline is built
+pub fn start(
+    filename: &str,
+    receiver: Receiver<BytesMut>,
+    options: RecordOptions,
+) -> Result<RecordingHandle> {
+    log::info!("Starting to record {}", filename);
+
+    let pipeline = pipeline(filename, receiver, None, &options)?;
+
+    Ok(RecordingHandle::spawn(pipeline, filename.into()))
 }
 
 #[cfg(test)]
@@ -108,11 +304,61 @@ mod tests {
     use crossbeam_channel::unbounded;
 
     use super::*;
-    use crate::rtp_stream::start;
+    use crate::record::SegmentOptions;
+    use crate::rtp_stream;
     use std::path::Path;
     use std::thread::sleep;
     use std::time::Duration;
 
+    #[test]
+    fn it_extends_rtp_timestamps_across_wraparound() {
+        let mut clock = RtpClock::new(90000, Instant::now());
+
+        assert_eq!(clock.extend(u32::MAX - 3000), i64::from(u32::MAX - 3000));
+        assert_eq!(clock.extend(2999), i64::from(u32::MAX) + 3000);
+        assert_eq!(clock.extend(u32::MAX), i64::from(u32::MAX));
+        assert_eq!(clock.extend(5999), i64::from(u32::MAX) + 6000);
+    }
+
+    #[test]
+    fn it_derives_pts_from_rtp_timestamps() {
+        let epoch = Instant::now();
+        let arrival = epoch + Duration::from_millis(40);
+        let mut clock = RtpClock::new(90000, epoch);
+
+        assert_eq!(clock.pts(u32::MAX - 2999, arrival), 40_000_000);
+        assert_eq!(clock.pts(3000, arrival), 106_666_666);
+        assert_eq!(clock.pts(u32::MAX - 5999, arrival), 6_666_667);
+
+        let packet = [0x80, 0x60, 0, 1, 0, 0, 0x0b, 0xb8, 0, 0, 0, 1];
+
+        assert_eq!(rtp_timestamp(&packet), Some(3000));
+        assert_eq!(rtp_timestamp(&packet[..8]), None);
+    }
+
+    #[test]
+    fn it_refuses_sdp_audio_it_would_not_record() {
+        let sdp = SessionDescription::parse(
+            "v=0\nm=video 5000 RTP/AVP 96\na=rtpmap:96 H264/90000\n\
+             m=audio 5002 RTP/AVP 111\na=rtpmap:111 opus/48000/2\n",
+        )
+        .unwrap();
+        let (_inbound_sender, inbound_receiver) = unbounded::<Command>();
+        let (outbound_sender, _outbound_receiver) = unbounded::<Command>();
+        let (_tx, rx) = unbounded::<BytesMut>();
+
+        assert!(matches!(
+            record_with_sdp(
+                "test/output/it_refuses_sdp_audio.mp4",
+                &sdp,
+                rx,
+                inbound_receiver,
+                outbound_sender,
+            ),
+            Err(MediaPipelineError::SdpError(_))
+        ));
+    }
+
     #[test]
     fn it_records_rtp_via_stream() {
         env_logger::try_init().ok();
@@ -122,7 +368,7 @@ mod tests {
         let (outbound_sender, outbound_receiver) = unbounded::<Command>();
 
         // start the rtp stream
-        let (_tx, rx) = start();
+        let (_tx, rx) = rtp_stream::start();
 
         // record the video in a separate thread
         std::thread::spawn(move || {
@@ -137,12 +383,107 @@ mod tests {
 
         // listen for commands
         while let Ok(command) = outbound_receiver.recv() {
-            if let Command::Stopped = command {
+            if let Command::Stopped(path) = command {
                 log::info!("received Command::Stopped");
-                // not a great assertion, but means we got to the end and the file exists
-                assert!(Path::new(filename).exists());
+                // the file is complete once Stopped is received
+                assert_eq!(path, Path::new(filename));
+                assert!(path.exists());
                 break;
             }
         }
     }
+
+    #[test]
+    fn it_records_segments_via_stream() {
+        env_logger::try_init().ok();
+
+        let filename = "test/output/it_records_segments_via_stream-%05d.mp4";
+        let options = RecordOptions::new()
+            .segments(SegmentOptions::new().max_duration(Duration::from_secs(1)));
+        let (inbound_sender, inbound_receiver) = unbounded::<Command>();
+        let (outbound_sender, outbound_receiver) = unbounded::<Command>();
+
+        // start the rtp stream
+        let (_tx, rx) = rtp_stream::start();
+
+        // record the video in a separate thread
+        std::thread::spawn(move || {
+            record_with_options(filename, rx, options, inbound_receiver, outbound_sender).unwrap();
+        });
+
+        // record a few segments
+        sleep(Duration::from_millis(3500));
+
+        // stop recording
+        inbound_sender.send(Command::Stop).unwrap();
+
+        let mut segments = vec![];
+
+        // listen for commands
+        while let Ok(command) = outbound_receiver.recv() {
+            match command {
+                Command::SegmentFinished(location) => segments.push(location),
+                Command::Stopped(last) => {
+                    assert_eq!(segments.last(), Some(&last));
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        assert!(!segments.is_empty());
+        assert!(segments.iter().all(|location| location.exists()));
+    }
+
+    #[test]
+    fn it_pauses_and_resumes_via_stream() {
+        env_logger::try_init().ok();
+
+        let filename = "test/output/it_pauses_and_resumes_via_stream.mp4";
+        let (inbound_sender, inbound_receiver) = unbounded::<Command>();
+        let (outbound_sender, outbound_receiver) = unbounded::<Command>();
+
+        // start the rtp stream
+        let (_tx, rx) = rtp_stream::start();
+
+        // record the video in a separate thread
+        std::thread::spawn(move || {
+            record(filename, rx, inbound_receiver, outbound_sender).unwrap();
+        });
+
+        sleep(Duration::from_millis(1000));
+        inbound_sender.send(Command::Pause).unwrap();
+        assert!(matches!(outbound_receiver.recv(), Ok(Command::Paused)));
+
+        sleep(Duration::from_millis(1000));
+        inbound_sender.send(Command::Resume).unwrap();
+        assert!(matches!(outbound_receiver.recv(), Ok(Command::Resumed)));
+
+        sleep(Duration::from_millis(1000));
+        inbound_sender.send(Command::Stop).unwrap();
+
+        while let Ok(command) = outbound_receiver.recv() {
+            if let Command::Stopped(path) = command {
+                assert!(path.exists());
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn it_records_with_a_handle() {
+        env_logger::try_init().ok();
+
+        let filename = "test/output/it_records_with_a_handle.mp4";
+        let (_tx, rx) = rtp_stream::start();
+        let recording = start(filename, rx, RecordOptions::default()).unwrap();
+
+        sleep(Duration::from_millis(2000));
+        assert!(recording.is_running());
+
+        let path = recording.stop().unwrap();
+
+        assert_eq!(path, Path::new(filename));
+        assert!(path.exists());
+    }
 }
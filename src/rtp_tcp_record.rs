@@ -0,0 +1,169 @@
+use crate::codec::RtpCodec;
+use crate::error::Result;
+use crate::main_loop::{main_loop, Command};
+use crate::record::RecordOptions;
+use crate::recording_handle::RecordingHandle;
+use crate::rtp_udp_client_record::{pipeline, PacketSource};
+use crate::tcp_source::TcpSourceConfig;
+use crossbeam_channel::{Receiver, Sender};
+use glib::MainLoop;
+
+// Record a stream received over TCP, framed by packet length (RFC 4571).  The
+// options are the ones of the UDP recorders, RTCP is still received over UDP.
+pub fn record(
+    source: TcpSourceConfig,
+    filename: &str,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    log::info!(
+        "Starting to record {} {} from {} over TCP",
+        options.codec().encoding_name(),
+        filename,
+        source.address()
+    );
+
+    pipeline(&source.into(), None, filename, &options).and_then(|pipeline| {
+        main_loop(pipeline, filename.into(), inbound_receiver, outbound_sender)
+    })
+}
+
+// Record a video and an audio stream received over separate TCP connections
+// into one file, the audio codec has to be set in the options
+pub fn record_with_audio(
+    video_source: TcpSourceConfig,
+    audio_source: TcpSourceConfig,
+    filename: &str,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    log::info!(
+        "Starting to record {} from {} (video) and {} (audio) over TCP",
+        filename,
+        video_source.address(),
+        audio_source.address()
+    );
+
+    let video_source = PacketSource::from(video_source);
+    let audio_source = PacketSource::from(audio_source);
+
+    pipeline(&video_source, Some(&audio_source), filename, &options).and_then(|pipeline| {
+        main_loop(pipeline, filename.into(), inbound_receiver, outbound_sender)
+    })
+}
+
+// Start recording in the background, returning once the pipeline is built
+pub fn start(
+    source: TcpSourceConfig,
+    filename: &str,
+    options: RecordOptions,
+) -> Result<RecordingHandle> {
+    log::info!(
+        "Starting to record {} {} from {} over TCP",
+        options.codec().encoding_name(),
+        filename,
+        source.address()
+    );
+
+    let pipeline = pipeline(&source.into(), None, filename, &options)?;
+
+    Ok(RecordingHandle::spawn(pipeline, filename.into()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+    use crate::codec::AudioCodec;
+    use crate::record::recording_pipeline;
+    use crate::rtp_tcp_server;
+    use crate::rtp_udp_client_record::NetworkSources;
+    use crate::rtp_udp_server::TestSourceConfig;
+    use crate::tcp_source::TcpMode;
+    use std::path::Path;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn it_builds_tcp_pipelines() {
+        let options = RecordOptions::new().audio_codec(AudioCodec::Opus);
+        let video = TcpSourceConfig::listen("0.0.0.0:5020".parse().unwrap());
+        let audio = TcpSourceConfig::connect("10.0.0.2:5022".parse().unwrap());
+        let launch = NetworkSources::new(&video.into(), Some(&audio.into()), &options)
+            .unwrap()
+            .pipeline(|video_source, audio_source| {
+                recording_pipeline("out.mkv", &options, video_source, audio_source)
+            })
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        assert!(launch.contains(
+            "tcpserversrc host=0.0.0.0 port=5020 do-timestamp=true ! \
+            application/x-rtp-stream, media=(string)video"
+        ));
+        assert!(launch.contains(
+            "! rtpstreamdepay ! application/x-rtp, media=(string)video, clock-rate=(int)90000"
+        ));
+        assert!(launch.contains(
+            "tcpclientsrc host=10.0.0.2 port=5022 do-timestamp=true ! \
+            application/x-rtp-stream, media=(string)audio"
+        ));
+        assert!(launch.contains("rtpjitterbuffer name=audio_jitterbuffer"));
+
+        let unreachable = TcpSourceConfig::connect("0.0.0.0:5020".parse().unwrap());
+
+        assert!(NetworkSources::new(&unreachable.into(), None, &options).is_err());
+    }
+
+    #[test]
+    fn it_records_rtp_via_tcp() {
+        env_logger::try_init().ok();
+
+        let filename = "test/output/it_records_rtp_via_tcp.mp4";
+        let (inbound_sender, inbound_receiver) = unbounded::<Command>();
+        let (outbound_sender, outbound_receiver) = unbounded::<Command>();
+
+        // start a tcp server listening for the recorder
+        let _server = rtp_tcp_server::start_with_config(
+            TestSourceConfig::new().destination("127.0.0.1", 5020),
+            TcpMode::Listen,
+        )
+        .unwrap();
+
+        sleep(Duration::from_millis(500));
+
+        // record the video in a separate thread
+        std::thread::spawn(move || {
+            let source = TcpSourceConfig::connect("127.0.0.1:5020".parse().unwrap());
+
+            record(
+                source,
+                filename,
+                RecordOptions::default(),
+                inbound_receiver,
+                outbound_sender,
+            )
+            .unwrap();
+        });
+
+        // record for 2 seconds
+        sleep(Duration::from_millis(2000));
+
+        // stop recording
+        inbound_sender.send(Command::Stop).unwrap();
+
+        // listen for commands
+        while let Ok(command) = outbound_receiver.recv() {
+            if let Command::Stopped(path) = command {
+                assert_eq!(path, Path::new(filename));
+                assert!(path.exists());
+                break;
+            }
+        }
+    }
+}
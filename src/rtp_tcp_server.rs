@@ -0,0 +1,74 @@
+use crate::error::Result;
+use crate::pipeline_builder::{Chain, ElementSpec, PipelineBuilder};
+use crate::rtp_udp_server::{sending_pipeline, start_pipeline, TestServer, TestSourceConfig};
+use crate::tcp_source::TcpMode;
+
+// The test streams framed by their length (RFC 4571), sent over a TCP
+// connection per stream.  Listening, the destination of the config is the
+// local address the receiver connects to.
+fn test_source_pipeline(config: &TestSourceConfig, mode: TcpMode) -> Result<PipelineBuilder> {
+    let factory = match mode {
+        TcpMode::Listen => "tcpserversink",
+        TcpMode::Connect => "tcpclientsink",
+    };
+
+    sending_pipeline(config, |name, port| {
+        Chain::new()
+            .element(ElementSpec::new("rtpstreampay"))
+            .element(
+                ElementSpec::new(factory)
+                    .name(name)
+                    .property("host", config.host())
+                    .property("port", port),
+            )
+    })
+}
+
+// Listen for a receiver on 127.0.0.1:5000
+pub fn start() -> Result<TestServer> {
+    start_with_config(TestSourceConfig::default(), TcpMode::Listen)
+}
+
+// Send the stream described by the config until it ends or is stopped.  A
+// listening server sends to the receivers connected, connecting fails right
+// away when the receiver is not listening yet.
+pub fn start_with_config(config: TestSourceConfig, mode: TcpMode) -> Result<TestServer> {
+    test_source_pipeline(&config, mode)?
+        .build()
+        .and_then(start_pipeline)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::codec::AudioCodec;
+
+    #[test]
+    fn it_builds_tcp_test_sources() {
+        let config = TestSourceConfig::new()
+            .destination("10.0.0.2", 6000)
+            .audio(AudioCodec::Opus, 6002);
+
+        let launch = test_source_pipeline(&config, TcpMode::Listen)
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        assert!(launch.contains(
+            "rtph264pay pt=96 ! rtpstreampay ! tcpserversink name=video_sink host=10.0.0.2 port=6000"
+        ));
+        assert!(launch.contains(
+            "rtpopuspay pt=111 ! rtpstreampay ! tcpserversink name=audio_sink host=10.0.0.2 port=6002"
+        ));
+
+        let launch = test_source_pipeline(&config, TcpMode::Connect)
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        assert!(
+            launch.contains("rtpstreampay ! tcpclientsink name=video_sink host=10.0.0.2 port=6000")
+        );
+    }
+}
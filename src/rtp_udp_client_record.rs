@@ -1,24 +1,234 @@
-use crate::create_pipeline;
-use crate::error::Result;
+use crate::codec::RtpCodec;
+use crate::error::{MediaPipelineError, Result};
+use crate::fec::{attach_fec, jitter_buffer_chain, FecOptions};
 use crate::main_loop::{main_loop, Command};
+use crate::pipeline_builder::{Chain, PipelineBuilder, PropertyValue};
+use crate::record::{recording_pipeline, RecordOptions};
+use crate::recording_handle::RecordingHandle;
+use crate::rtcp::{attach_retransmission, session_chains, AUDIO_SESSION, VIDEO_SESSION};
+use crate::sdp::RtpFormat;
+use crate::srtp::{attach_srtp, decoder_name, decrypted_chain, SrtpOptions};
+use crate::tcp_source::TcpSourceConfig;
+use crate::udp_source::UdpSourceConfig;
 use crossbeam_channel::{Receiver, Sender};
 use glib::MainLoop;
 use gstreamer::Pipeline;
 
-fn pipeline(port: &str, filename: &str) -> Result<Pipeline> {
-    let launch = format!(
-        "udpsrc port={}  \
-            ! application/x-rtp, media=(string)video, clock-rate=(int)90000, encoding-name=(string)H264, payload=(int)96
-            ! queue  \
-                ! rtph264depay name=pay0 \
-                ! h264parse config-interval=-1 \
-                ! mp4mux \
-            ! filesink location={}",
-            port,
-            filename
-    );
+// Where the packets of a received stream come from
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PacketSource {
+    Udp(UdpSourceConfig),
+    Tcp(TcpSourceConfig),
+}
+
+impl From<UdpSourceConfig> for PacketSource {
+    fn from(source: UdpSourceConfig) -> Self {
+        PacketSource::Udp(source)
+    }
+}
+
+impl From<TcpSourceConfig> for PacketSource {
+    fn from(source: TcpSourceConfig) -> Self {
+        PacketSource::Tcp(source)
+    }
+}
+
+impl PacketSource {
+    fn validate(&self) -> Result<()> {
+        match self {
+            PacketSource::Udp(source) => source.validate(),
+            PacketSource::Tcp(source) => source.validate(),
+        }
+    }
+}
+
+// A source producing RTP in a format, decrypted if it is received as SRTP
+fn source_chain(source: &PacketSource, format: &RtpFormat, srtp: Option<&SrtpOptions>) -> Chain {
+    let source = match source {
+        PacketSource::Udp(source) => Chain::new().element(source.element()),
+        PacketSource::Tcp(source) => source.chain(format, srtp.is_some()),
+    };
+
+    match srtp {
+        Some(_) => decrypted_chain(source, format),
+        None => source.caps(format.caps()),
+    }
+}
+
+// A stream with its RED packets unwrapped if it is protected by FEC
+fn unwrap_red(chain: Chain, fec: Option<&FecOptions>) -> Chain {
+    match fec {
+        Some(fec) => fec.unwrap_red(chain),
+        None => chain,
+    }
+}
+
+// The video and (if there is an audio source) audio streams of a recording
+// received over UDP or TCP, reordered by jitter buffers and with lost packets
+// recovered if they are protected by FEC
+pub(crate) struct NetworkSources {
+    video: Chain,
+    audio: Option<Chain>,
+
+    // the rtpbin and what goes in and out of it besides the received streams
+    sessions: Vec<Chain>,
+}
+
+impl NetworkSources {
+    // Without RTCP a jitter buffer per stream is enough, otherwise the streams
+    // go through the sessions of an rtpbin
+    pub(crate) fn new(
+        source: &PacketSource,
+        audio_source: Option<&PacketSource>,
+        options: &RecordOptions,
+    ) -> Result<Self> {
+        let jitter_buffer = options.jitter_buffer_options();
+        let video_format = options.video_format();
+        let audio = match audio_source {
+            Some(audio_source) => Some((audio_source, options.required_audio_format()?)),
+            None => None,
+        };
+
+        for source in std::iter::once(source).chain(audio_source) {
+            source.validate()?;
+        }
+
+        let rtcp = [options.rtcp_options(), options.audio_rtcp_options()];
+        let fec = [options.fec_options(), options.audio_fec_options()];
+        let srtp = [options.srtp_options(), options.audio_srtp_options()];
+
+        for srtp in srtp.iter().flatten() {
+            srtp.validate()?;
+        }
+
+        // receiver reports would go out unencrypted
+        let encrypted_reports = rtcp.iter().zip(&srtp).any(|(rtcp, srtp)| {
+            srtp.is_some() && matches!(rtcp, Some(rtcp) if rtcp.sends_reports())
+        });
+
+        if encrypted_reports {
+            return Err(MediaPipelineError::RecordOptionsError(
+                "RTCP of SRTP streams cannot be sent back".to_owned(),
+            ));
+        }
+
+        if rtcp.iter().all(Option::is_none) {
+            let audio = match audio {
+                Some((audio_source, audio_format)) => Some(jitter_buffer_chain(
+                    "audio",
+                    source_chain(audio_source, &audio_format, options.audio_srtp_options()),
+                    jitter_buffer,
+                    options.audio_fec_options(),
+                )?),
+                None => None,
+            };
 
-    let pipeline = create_pipeline(&launch)?;
+            return Ok(NetworkSources {
+                video: jitter_buffer_chain(
+                    "video",
+                    source_chain(source, &video_format, options.srtp_options()),
+                    jitter_buffer,
+                    options.fec_options(),
+                )?,
+                audio,
+                sessions: Vec::new(),
+            });
+        }
+
+        let mut rtpbin = jitter_buffer.rtpbin();
+
+        let payload_types = [
+            Some(video_format.payload_type),
+            audio.as_ref().map(|(_, format)| format.payload_type),
+        ];
+
+        for (rtcp, payload_type) in rtcp.iter().zip(&payload_types) {
+            if let (Some(rtcp), Some(payload_type)) = (rtcp, payload_type) {
+                rtcp.validate(*payload_type)?;
+            }
+        }
+
+        for fec in fec.iter().flatten() {
+            fec.validate()?;
+        }
+
+        // the FEC decoders the sessions request recover the packets reported lost
+        if fec.iter().flatten().any(|fec| fec.recovers()) {
+            rtpbin = rtpbin.property("do-lost", true);
+        }
+
+        // NACKs are RTCP feedback of the AVPF profile, the jitter buffers of the
+        // sessions using them ask for retransmissions once the pipeline is built
+        if rtcp.iter().flatten().any(|rtcp| rtcp.uses_nack()) {
+            rtpbin = rtpbin.property("rtp-profile", PropertyValue::Nick("avpf".into()));
+        }
+
+        let mut sessions = vec![Chain::new().element(rtpbin)];
+        let (video, video_sessions) = session_chains(
+            VIDEO_SESSION,
+            unwrap_red(
+                source_chain(source, &video_format, options.srtp_options()),
+                options.fec_options(),
+            ),
+            &video_format,
+            options.rtcp_options(),
+            options.srtp_options().map(|_| decoder_name("video")),
+        );
+
+        sessions.extend(video_sessions);
+
+        let audio = audio.map(|(audio_source, audio_format)| {
+            let (audio, audio_sessions) = session_chains(
+                AUDIO_SESSION,
+                unwrap_red(
+                    source_chain(audio_source, &audio_format, options.audio_srtp_options()),
+                    options.audio_fec_options(),
+                ),
+                &audio_format,
+                options.audio_rtcp_options(),
+                options.audio_srtp_options().map(|_| decoder_name("audio")),
+            );
+
+            sessions.extend(audio_sessions);
+            audio
+        });
+
+        Ok(NetworkSources {
+            video,
+            audio,
+            sessions,
+        })
+    }
+
+    // Build the pipeline depayloading the video and audio, with the sessions
+    pub(crate) fn pipeline<F>(self, depayload: F) -> Result<PipelineBuilder>
+    where
+        F: FnOnce(Chain, Option<Chain>) -> Result<PipelineBuilder>,
+    {
+        let builder = depayload(self.video, self.audio)?;
+
+        Ok(self
+            .sessions
+            .into_iter()
+            .fold(builder, PipelineBuilder::chain))
+    }
+}
+
+pub(crate) fn pipeline(
+    source: &PacketSource,
+    audio_source: Option<&PacketSource>,
+    filename: &str,
+    options: &RecordOptions,
+) -> Result<Pipeline> {
+    let pipeline = NetworkSources::new(source, audio_source, options)?
+        .pipeline(|video_source, audio_source| {
+            recording_pipeline(filename, options, video_source, audio_source)
+        })?
+        .build()?;
+
+    attach_srtp(&pipeline, options)?;
+    attach_retransmission(&pipeline, options)?;
+    attach_fec(&pipeline, options)?;
 
     Ok(pipeline)
 }
@@ -29,10 +239,138 @@ pub fn record(
     inbound_receiver: Receiver<Command>,
     outbound_sender: Sender<Command>,
 ) -> Result<MainLoop> {
-    log::info!("Starting to record {} from port {}", filename, port);
+    record_with_options(
+        port,
+        filename,
+        RecordOptions::default(),
+        inbound_receiver,
+        outbound_sender,
+    )
+}
 
-    pipeline(port, filename)
-        .and_then(|pipeline| main_loop(pipeline, inbound_receiver, outbound_sender))
+pub fn record_with_options(
+    port: &str,
+    filename: &str,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    UdpSourceConfig::from_port(port).and_then(|source| {
+        record_from(source, filename, options, inbound_receiver, outbound_sender)
+    })
+}
+
+// Record a stream received on an address, e.g. a multicast group
+pub fn record_from(
+    source: UdpSourceConfig,
+    filename: &str,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    log::info!(
+        "Starting to record {} {} from {}",
+        options.codec().encoding_name(),
+        filename,
+        source.address()
+    );
+
+    pipeline(&source.into(), None, filename, &options).and_then(|pipeline| {
+        main_loop(pipeline, filename.into(), inbound_receiver, outbound_sender)
+    })
+}
+
+// Record a video and an audio stream received on separate ports into one
+// file, the audio codec has to be set in the options
+pub fn record_with_audio(
+    video_port: &str,
+    audio_port: &str,
+    filename: &str,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    let video_source = UdpSourceConfig::from_port(video_port)?;
+    let audio_source = UdpSourceConfig::from_port(audio_port)?;
+
+    record_from_with_audio(
+        video_source,
+        audio_source,
+        filename,
+        options,
+        inbound_receiver,
+        outbound_sender,
+    )
+}
+
+// Record a video and an audio stream received on separate addresses into one
+// file, the audio codec has to be set in the options
+pub fn record_from_with_audio(
+    video_source: UdpSourceConfig,
+    audio_source: UdpSourceConfig,
+    filename: &str,
+    options: RecordOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    log::info!(
+        "Starting to record {} from {} (video) and {} (audio)",
+        filename,
+        video_source.address(),
+        audio_source.address()
+    );
+
+    let video_source = video_source.into();
+    let audio_source = audio_source.into();
+
+    pipeline(&video_source, Some(&audio_source), filename, &options).and_then(|pipeline| {
+        main_loop(pipeline, filename.into(), inbound_receiver, outbound_sender)
+    })
+}
+
+// Start recording in the background, returning once the pipeline is built
+pub fn start(port: &str, filename: &str, options: RecordOptions) -> Result<RecordingHandle> {
+    UdpSourceConfig::from_port(port).and_then(|source| start_from(source, filename, options))
+}
+
+// Start recording a stream received on an address in the background
+pub fn start_from(
+    source: UdpSourceConfig,
+    filename: &str,
+    options: RecordOptions,
+) -> Result<RecordingHandle> {
+    log::info!(
+        "Starting to record {} {} from {}",
+        options.codec().encoding_name(),
+        filename,
+        source.address()
+    );
+
+    let pipeline = pipeline(&source.into(), None, filename, &options)?;
+
+    Ok(RecordingHandle::spawn(pipeline, filename.into()))
+}
+
+// Start recording a video and an audio stream received on separate addresses
+// in the background, the audio codec has to be set in the options
+pub fn start_from_with_audio(
+    video_source: UdpSourceConfig,
+    audio_source: UdpSourceConfig,
+    filename: &str,
+    options: RecordOptions,
+) -> Result<RecordingHandle> {
+    log::info!(
+        "Starting to record {} from {} (video) and {} (audio)",
+        filename,
+        video_source.address(),
+        audio_source.address()
+    );
+
+    let video_source = video_source.into();
+    let audio_source = audio_source.into();
+    let pipeline = pipeline(&video_source, Some(&audio_source), filename, &options)?;
+
+    Ok(RecordingHandle::spawn(pipeline, filename.into()))
 }
 
 #[cfg(test)]
@@ -41,11 +379,169 @@ mod tests {
     use crossbeam_channel::unbounded;
 
     use super::*;
-    use crate::rtp_udp_server::start;
+    use crate::codec::AudioCodec;
+    use crate::rtcp::{RtcpOptions, RtxOptions};
+    use crate::rtp_udp_server;
+    use crate::srtp::{SrtpKey, SrtpSuite};
     use std::path::Path;
     use std::thread::sleep;
     use std::time::Duration;
 
+    #[test]
+    fn it_builds_rtcp_pipelines() {
+        let options = RecordOptions::new()
+            .audio_codec(AudioCodec::Pcmu)
+            .rtcp(RtcpOptions::new(UdpSourceConfig::on_port(5001)).send_to("10.0.0.1", 5005));
+        let launch = NetworkSources::new(
+            &UdpSourceConfig::on_port(5000).into(),
+            Some(&UdpSourceConfig::on_port(5002).into()),
+            &options,
+        )
+        .unwrap()
+        .pipeline(|video_source, audio_source| {
+            recording_pipeline("out.mkv", &options, video_source, audio_source)
+        })
+        .unwrap()
+        .launch()
+        .unwrap();
+
+        assert!(launch
+            .contains("rtpbin name=rtpbin latency=200 drop-on-latency=false buffer-mode=slave"));
+        assert!(launch.contains("udpsrc port=5000 ! application/x-rtp"));
+        assert!(launch.contains("! rtpbin.recv_rtp_sink_0"));
+        assert!(launch.contains("! rtpbin.recv_rtp_sink_1"));
+        assert!(launch.contains("udpsrc port=5001 ! application/x-rtcp ! rtpbin.recv_rtcp_sink_0"));
+        assert!(launch.contains(
+            "rtpbin.send_rtcp_src_0 ! udpsink host=10.0.0.1 port=5005 sync=false async=false"
+        ));
+        assert!(launch.contains("rtpbin. ! application/x-rtp, media=(string)video"));
+        assert!(!launch.contains("rtpjitterbuffer"));
+
+        // the RTCP of a multicast stream comes through the group as well
+        let group = |port: u16| UdpSourceConfig::new(([239, 1, 1, 1], port).into());
+        let multicast = RecordOptions::new().rtcp(RtcpOptions::new(group(5001)));
+        let launch = NetworkSources::new(&group(5000).into(), None, &multicast)
+            .unwrap()
+            .pipeline(|video_source, audio_source| {
+                recording_pipeline("out.mkv", &multicast, video_source, audio_source)
+            })
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        assert!(launch.contains(
+            "udpsrc address=239.1.1.1 port=5001 ! application/x-rtcp ! rtpbin.recv_rtcp_sink_0"
+        ));
+
+        let options = options.rtcp(
+            RtcpOptions::new(UdpSourceConfig::on_port(5001))
+                .send_to("10.0.0.1", 5005)
+                .rtx(RtxOptions::new(97)),
+        );
+        let launch = NetworkSources::new(&UdpSourceConfig::on_port(5000).into(), None, &options)
+            .unwrap()
+            .pipeline(|video_source, audio_source| {
+                recording_pipeline("out.mkv", &options, video_source, audio_source)
+            })
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        // linked to the rtpbin once its RTX receiver can be set up
+        assert!(launch.contains("rtp-profile=avpf"));
+        assert!(!launch.contains("do-retransmission"));
+        assert!(launch.contains("identity name=video_rtp_source"));
+        assert!(!launch.contains("video_rtp_source !"));
+        assert!(!launch.contains("rtpbin.recv_rtp_sink_0"));
+
+        let options = options.fec(FecOptions::new().red(122).ulpfec(123));
+        let launch = NetworkSources::new(&UdpSourceConfig::on_port(5000).into(), None, &options)
+            .unwrap()
+            .pipeline(|video_source, audio_source| {
+                recording_pipeline("out.mkv", &options, video_source, audio_source)
+            })
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        // the rtpbin stores the packets and requests the FEC decoder
+        assert!(launch.contains("buffer-mode=slave do-lost=true"));
+        assert!(launch.contains("! rtpreddec pt=122 ! identity name=video_rtp_source"));
+        assert!(!launch.contains("rtpulpfecdec"));
+
+        let options = options.rtcp(RtcpOptions::new(UdpSourceConfig::on_port(5001)).nack(true));
+
+        assert!(
+            NetworkSources::new(&UdpSourceConfig::on_port(5000).into(), None, &options).is_err()
+        );
+    }
+
+    #[test]
+    fn it_builds_srtp_pipelines() {
+        let key = SrtpKey::new(SrtpSuite::AesCm128HmacSha1_80, &[1; 16], &[2; 14]);
+        let launch = |options: &RecordOptions| {
+            NetworkSources::new(&UdpSourceConfig::on_port(5000).into(), None, options)?
+                .pipeline(|video_source, audio_source| {
+                    recording_pipeline("out.mkv", options, video_source, audio_source)
+                })?
+                .launch()
+        };
+
+        let options = RecordOptions::new().srtp(SrtpOptions::new(key.clone()));
+        let plain = launch(&options).unwrap();
+
+        assert!(plain.contains(
+            "udpsrc port=5000 ! application/x-srtp, media=(string)video, clock-rate=(int)90000"
+        ));
+        assert!(plain.contains("! srtpdec name=video_srtpdec ! application/x-rtp"));
+
+        let options = options.rtcp(RtcpOptions::new(UdpSourceConfig::on_port(5001)));
+        let rtcp = launch(&options).unwrap();
+
+        assert!(rtcp.contains("udpsrc port=5001 ! application/x-srtcp ! video_srtpdec.rtcp_sink"));
+        assert!(rtcp.contains("video_srtpdec.rtcp_src ! identity ! rtpbin.recv_rtcp_sink_0"));
+
+        assert!(launch(
+            &options
+                .clone()
+                .rtcp(RtcpOptions::new(UdpSourceConfig::on_port(5001)).send_to("10.0.0.1", 5005))
+        )
+        .is_err());
+        assert!(launch(&options.srtp(SrtpOptions::new(SrtpKey::new(
+            SrtpSuite::AeadAes128Gcm,
+            &[1; 16],
+            &[2; 14]
+        ))))
+        .is_err());
+    }
+
+    #[test]
+    fn it_builds_multicast_pipelines() {
+        let options = RecordOptions::new().audio_codec(AudioCodec::Opus);
+        let video = UdpSourceConfig::new("239.1.1.1:5004".parse().unwrap())
+            .multicast_iface("eth1")
+            .buffer_size(1 << 22);
+        let audio = UdpSourceConfig::new("[ff15::1]:5006".parse().unwrap());
+        let launch = NetworkSources::new(&video.into(), Some(&audio.into()), &options)
+            .unwrap()
+            .pipeline(|video_source, audio_source| {
+                recording_pipeline("out.mkv", &options, video_source, audio_source)
+            })
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        assert!(launch.contains(
+            "udpsrc address=239.1.1.1 port=5004 multicast-iface=eth1 buffer-size=4194304 \
+            ! application/x-rtp, media=(string)video"
+        ));
+        assert!(launch.contains("udpsrc address=ff15::1 port=5006 ! application/x-rtp"));
+
+        let unicast = UdpSourceConfig::on_port(5004).multicast_iface("eth1");
+
+        assert!(NetworkSources::new(&unicast.into(), None, &options).is_err());
+    }
+
     #[test]
     fn it_records_rtp_via_udp() {
         env_logger::try_init().ok();
@@ -55,9 +551,7 @@ mod tests {
         let (outbound_sender, outbound_receiver) = unbounded::<Command>();
 
         // start a udp server
-        std::thread::spawn(move || {
-            let (_, _) = start();
-        });
+        let _server = rtp_udp_server::start().unwrap();
 
         // record the video in a separate thread
         std::thread::spawn(move || {
@@ -72,9 +566,10 @@ mod tests {
 
         // listen for commands
         while let Ok(command) = outbound_receiver.recv() {
-            if let Command::Stopped = command {
+            if let Command::Stopped(path) = command {
                 log::info!("received Command::Stopped");
-                assert!(Path::new(filename).exists());
+                assert_eq!(path, Path::new(filename));
+                assert!(path.exists());
                 break;
             }
         }
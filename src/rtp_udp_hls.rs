@@ -0,0 +1,500 @@
+use crate::codec::RtpCodec;
+use crate::container::Container;
+use crate::error::{MediaPipelineError, Result};
+use crate::fec::attach_fec;
+use crate::main_loop::{main_loop, Command};
+use crate::pipeline_builder::{Chain, ElementSpec, PipelineBuilder};
+use crate::record::{depayload_pipeline, RecordOptions, Sink};
+use crate::recording_handle::RecordingHandle;
+use crate::rtcp::attach_retransmission;
+use crate::rtp_udp_client_record::{NetworkSources, PacketSource};
+use crate::srtp::attach_srtp;
+use crate::udp_source::UdpSourceConfig;
+use crossbeam_channel::{Receiver, Sender};
+use glib::MainLoop;
+use gstreamer::Pipeline;
+use std::path::Path;
+use std::time::Duration;
+
+const PLAYLIST: &str = "playlist.m3u8";
+
+// How the media segments of a playlist are stored
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HlsSegmentFormat {
+    // MPEG-TS segments, video and audio
+    Ts,
+
+    // fragmented MP4 (CMAF) segments with an init segment, video only.  Needs
+    // hlscmafsink from the hlssink3 plugin of gst-plugins-rs, which the usual
+    // plugin packages do not include.
+    Fmp4,
+}
+
+impl HlsSegmentFormat {
+    fn container(&self) -> Container {
+        match self {
+            HlsSegmentFormat::Ts => Container::MpegTs,
+            HlsSegmentFormat::Fmp4 => Container::FragmentedMp4,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HlsOptions {
+    segment_format: HlsSegmentFormat,
+    target_duration: Duration,
+    playlist_length: u32,
+    delete_old_segments: bool,
+}
+
+impl Default for HlsOptions {
+    fn default() -> Self {
+        HlsOptions {
+            segment_format: HlsSegmentFormat::Ts,
+            target_duration: Duration::from_secs(6),
+            playlist_length: 5,
+            delete_old_segments: true,
+        }
+    }
+}
+
+impl HlsOptions {
+    pub fn new() -> Self {
+        HlsOptions::default()
+    }
+
+    pub fn segment_format(mut self, segment_format: HlsSegmentFormat) -> Self {
+        self.segment_format = segment_format;
+        self
+    }
+
+    // Segments are cut at the first keyframe after this duration, it is
+    // rounded down to whole seconds
+    pub fn target_duration(mut self, target_duration: Duration) -> Self {
+        self.target_duration = target_duration;
+        self
+    }
+
+    // Number of segments listed in the playlist
+    pub fn playlist_length(mut self, playlist_length: u32) -> Self {
+        self.playlist_length = playlist_length;
+        self
+    }
+
+    // Delete segments that dropped out of the playlist.  One more segment than
+    // the playlist lists is kept for viewers still downloading it.
+    pub fn delete_old_segments(mut self, delete_old_segments: bool) -> Self {
+        self.delete_old_segments = delete_old_segments;
+        self
+    }
+
+    fn sink(&self, directory: &str) -> Result<Sink> {
+        let target_duration = self.target_duration.as_secs();
+
+        if target_duration == 0 {
+            return Err(hls_error("target duration must be at least a second"));
+        }
+
+        if self.playlist_length == 0 {
+            return Err(hls_error("playlist length must be at least one"));
+        }
+
+        let max_files = if self.delete_old_segments {
+            self.playlist_length + 1
+        } else {
+            0
+        };
+        let container = self.segment_format.container();
+
+        let sink = match self.segment_format {
+            HlsSegmentFormat::Ts => Sink {
+                chain: Chain::new().element(
+                    ElementSpec::new("hlssink2")
+                        .name("mux")
+                        .property("location", location(directory, "segment%05d.ts")?)
+                        .property("playlist-location", location(directory, PLAYLIST)?)
+                        .property("target-duration", target_duration)
+                        .property("playlist-length", self.playlist_length)
+                        .property("max-files", max_files),
+                ),
+                container,
+                video_pad: Some("video"),
+                audio_pad: Some("audio"),
+            },
+            HlsSegmentFormat::Fmp4 => Sink {
+                chain: Chain::new().element(
+                    ElementSpec::new("hlscmafsink")
+                        .name("mux")
+                        .property("location", location(directory, "segment%05d.m4s")?)
+                        .property("init-location", location(directory, "init%05d.mp4")?)
+                        .property("playlist-location", location(directory, PLAYLIST)?)
+                        .property("target-duration", target_duration)
+                        .property("playlist-length", self.playlist_length)
+                        .property("max-num-segment-files", max_files),
+                ),
+                container,
+                video_pad: None,
+                audio_pad: None,
+            },
+        };
+
+        Ok(sink)
+    }
+}
+
+fn hls_error(message: &str) -> MediaPipelineError {
+    MediaPipelineError::RecordOptionsError(format!("HLS {}", message))
+}
+
+// A file in the output directory, `%` of the directory escaped since
+// the sinks format the segment number into the location
+fn location(directory: &str, file: &str) -> Result<String> {
+    let directory = directory.replace('%', "%%");
+
+    Path::new(&directory)
+        .join(file)
+        .to_str()
+        .map(str::to_owned)
+        .ok_or_else(|| hls_error("directory is not valid UTF-8"))
+}
+
+fn hls_pipeline(
+    source: &UdpSourceConfig,
+    audio_source: Option<&UdpSourceConfig>,
+    directory: &str,
+    options: &RecordOptions,
+    hls: &HlsOptions,
+) -> Result<PipelineBuilder> {
+    if audio_source.is_some() && hls.segment_format == HlsSegmentFormat::Fmp4 {
+        return Err(hls_error("fMP4 segments cannot store audio"));
+    }
+
+    let sink = hls.sink(directory)?;
+    let source = PacketSource::from(source.clone());
+    let audio_source = audio_source.cloned().map(PacketSource::from);
+
+    NetworkSources::new(&source, audio_source.as_ref(), options)?.pipeline(
+        |video_source, audio_source| depayload_pipeline(options, sink, video_source, audio_source),
+    )
+}
+
+fn pipeline(
+    source: &UdpSourceConfig,
+    audio_source: Option<&UdpSourceConfig>,
+    directory: &str,
+    options: &RecordOptions,
+    hls: &HlsOptions,
+) -> Result<Pipeline> {
+    let builder = hls_pipeline(source, audio_source, directory, options, hls)?;
+
+    std::fs::create_dir_all(directory)?;
+
+    let pipeline = builder.build()?;
+
+    attach_srtp(&pipeline, options)?;
+    attach_retransmission(&pipeline, options)?;
+    attach_fec(&pipeline, options)?;
+
+    Ok(pipeline)
+}
+
+// Write the RTP received on a port as a live HLS playlist into a directory.
+// The container and segments of the record options are not used, the HLS
+// options decide how segments are written.
+pub fn record(
+    port: &str,
+    directory: &str,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    record_with_options(
+        port,
+        directory,
+        RecordOptions::default(),
+        HlsOptions::default(),
+        inbound_receiver,
+        outbound_sender,
+    )
+}
+
+pub fn record_with_options(
+    port: &str,
+    directory: &str,
+    options: RecordOptions,
+    hls: HlsOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    UdpSourceConfig::from_port(port).and_then(|source| {
+        record_from(
+            source,
+            directory,
+            options,
+            hls,
+            inbound_receiver,
+            outbound_sender,
+        )
+    })
+}
+
+// Write the RTP received on an address, e.g. a multicast group, as a live HLS
+// playlist into a directory
+pub fn record_from(
+    source: UdpSourceConfig,
+    directory: &str,
+    options: RecordOptions,
+    hls: HlsOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    log::info!(
+        "Starting HLS output of {} from {} to {}",
+        options.codec().encoding_name(),
+        source.address(),
+        directory
+    );
+
+    pipeline(&source, None, directory, &options, &hls).and_then(|pipeline| {
+        main_loop(
+            pipeline,
+            Path::new(directory).join(PLAYLIST),
+            inbound_receiver,
+            outbound_sender,
+        )
+    })
+}
+
+// Write a video and an audio stream received on separate ports as one
+// playlist, the audio codec has to be set in the options
+pub fn record_with_audio(
+    video_port: &str,
+    audio_port: &str,
+    directory: &str,
+    options: RecordOptions,
+    hls: HlsOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    let video_source = UdpSourceConfig::from_port(video_port)?;
+    let audio_source = UdpSourceConfig::from_port(audio_port)?;
+
+    record_from_with_audio(
+        video_source,
+        audio_source,
+        directory,
+        options,
+        hls,
+        inbound_receiver,
+        outbound_sender,
+    )
+}
+
+// Write a video and an audio stream received on separate addresses as one
+// playlist, the audio codec has to be set in the options
+pub fn record_from_with_audio(
+    video_source: UdpSourceConfig,
+    audio_source: UdpSourceConfig,
+    directory: &str,
+    options: RecordOptions,
+    hls: HlsOptions,
+    inbound_receiver: Receiver<Command>,
+    outbound_sender: Sender<Command>,
+) -> Result<MainLoop> {
+    log::info!(
+        "Starting HLS output to {} from {} (video) and {} (audio)",
+        directory,
+        video_source.address(),
+        audio_source.address()
+    );
+
+    pipeline(
+        &video_source,
+        Some(&audio_source),
+        directory,
+        &options,
+        &hls,
+    )
+    .and_then(|pipeline| {
+        main_loop(
+            pipeline,
+            Path::new(directory).join(PLAYLIST),
+            inbound_receiver,
+            outbound_sender,
+        )
+    })
+}
+
+// Start the HLS output in the background, returning once the pipeline is built
+pub fn start(
+    port: &str,
+    directory: &str,
+    options: RecordOptions,
+    hls: HlsOptions,
+) -> Result<RecordingHandle> {
+    UdpSourceConfig::from_port(port).and_then(|source| start_from(source, directory, options, hls))
+}
+
+// Start the HLS output of a stream received on an address in the background
+pub fn start_from(
+    source: UdpSourceConfig,
+    directory: &str,
+    options: RecordOptions,
+    hls: HlsOptions,
+) -> Result<RecordingHandle> {
+    log::info!(
+        "Starting HLS output of {} from {} to {}",
+        options.codec().encoding_name(),
+        source.address(),
+        directory
+    );
+
+    let pipeline = pipeline(&source, None, directory, &options, &hls)?;
+
+    Ok(RecordingHandle::spawn(
+        pipeline,
+        Path::new(directory).join(PLAYLIST),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+    use crate::codec::{AudioCodec, VideoCodec};
+    use crate::rtp_udp_server;
+    use std::thread::sleep;
+
+    #[test]
+    fn it_builds_hls_pipelines() {
+        let options = RecordOptions::new().audio_codec(AudioCodec::Opus);
+        let hls = HlsOptions::new()
+            .target_duration(Duration::from_secs(2))
+            .playlist_length(3);
+
+        let video = UdpSourceConfig::on_port(5000);
+        let audio = UdpSourceConfig::on_port(5002);
+        let launch = hls_pipeline(&video, Some(&audio), "live", &options, &hls)
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        assert!(launch.starts_with(
+            "hlssink2 name=mux location=live/segment%05d.ts \
+            playlist-location=live/playlist.m3u8 target-duration=2 playlist-length=3 max-files=4"
+        ));
+        assert!(launch.contains("opusparse ! identity name=audio_gate ! mux.audio"));
+        assert!(launch.contains(
+            "rtpjitterbuffer name=video_jitterbuffer latency=200 drop-on-latency=false mode=slave"
+        ));
+
+        let hls = hls.segment_format(HlsSegmentFormat::Fmp4);
+
+        assert!(hls_pipeline(&video, Some(&audio), "live", &options, &hls).is_err());
+        assert!(hls_pipeline(&video, None, "live", &options, &hls).is_ok());
+
+        let vp8 = RecordOptions::new().video_codec(VideoCodec::Vp8);
+
+        assert!(hls_pipeline(&video, None, "live", &vp8, &HlsOptions::new()).is_err());
+
+        let group = UdpSourceConfig::new("239.1.1.1:5000".parse().unwrap()).multicast_iface("eth1");
+        let launch = hls_pipeline(&group, None, "live", &options, &HlsOptions::new())
+            .unwrap()
+            .launch()
+            .unwrap();
+
+        assert!(launch.contains("udpsrc address=239.1.1.1 port=5000 multicast-iface=eth1"));
+        assert!(HlsOptions::new()
+            .target_duration(Duration::from_millis(500))
+            .sink("live")
+            .is_err());
+    }
+
+    #[test]
+    fn it_writes_fmp4_hls_via_udp() {
+        env_logger::try_init().ok();
+
+        gstreamer::init().unwrap();
+
+        // hlscmafsink is only in gst-plugins-rs
+        if gstreamer::ElementFactory::find("hlscmafsink").is_none() {
+            log::warn!("Skipping fMP4 HLS, hlscmafsink is not installed");
+            return;
+        }
+
+        let directory = "test/output/it_writes_fmp4_hls_via_udp";
+        let hls = HlsOptions::new()
+            .segment_format(HlsSegmentFormat::Fmp4)
+            .target_duration(Duration::from_secs(1));
+        let (inbound_sender, inbound_receiver) = unbounded::<Command>();
+        let (outbound_sender, outbound_receiver) = unbounded::<Command>();
+
+        let _server = rtp_udp_server::start_with_config(
+            rtp_udp_server::TestSourceConfig::new().destination("127.0.0.1", 5016),
+        )
+        .unwrap();
+
+        std::thread::spawn(move || {
+            record_with_options(
+                "5016",
+                directory,
+                RecordOptions::default(),
+                hls,
+                inbound_receiver,
+                outbound_sender,
+            )
+            .unwrap();
+        });
+
+        sleep(Duration::from_millis(3500));
+
+        inbound_sender.send(Command::Stop).unwrap();
+
+        while let Ok(command) = outbound_receiver.recv() {
+            if let Command::Stopped(playlist) = command {
+                assert!(playlist.exists());
+                assert!(Path::new(directory).join("init00000.mp4").exists());
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn it_writes_hls_via_udp() {
+        env_logger::try_init().ok();
+
+        let directory = "test/output/it_writes_hls_via_udp";
+        let hls = HlsOptions::new().target_duration(Duration::from_secs(1));
+        let (inbound_sender, inbound_receiver) = unbounded::<Command>();
+        let (outbound_sender, outbound_receiver) = unbounded::<Command>();
+
+        // start a udp server
+        let _server = rtp_udp_server::start().unwrap();
+
+        std::thread::spawn(move || {
+            record_with_options(
+                "5000",
+                directory,
+                RecordOptions::default(),
+                hls,
+                inbound_receiver,
+                outbound_sender,
+            )
+            .unwrap();
+        });
+
+        // write a few segments
+        sleep(Duration::from_millis(3500));
+
+        inbound_sender.send(Command::Stop).unwrap();
+
+        while let Ok(command) = outbound_receiver.recv() {
+            if let Command::Stopped(playlist) = command {
+                assert_eq!(playlist, Path::new(directory).join(PLAYLIST));
+                assert!(playlist.exists());
+                break;
+            }
+        }
+    }
+}
@@ -1,27 +1,557 @@
-use crate::create_pipeline;
-use crate::error::Result;
+use crate::codec::{AudioCodec, RtpCodec, VideoCodec};
+use crate::error::{MediaPipelineError, Result};
 use crate::main_loop::main_loop_simple;
-use bytes::Bytes;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use crate::pipeline_builder::{CapsSpec, Chain, ElementSpec, PipelineBuilder, PropertyValue};
+use crossbeam_channel::{unbounded, Receiver};
+use gio::prelude::*;
+use gstreamer::prelude::*;
+use gstreamer::Pipeline;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
 
-fn pipeline() -> Result<gstreamer::Pipeline> {
-    let launch = "videotestsrc ! video/x-raw,format=I420,framerate=30/1,width=1280,height=720 ! x264enc tune=zerolatency ! rtph264pay ! udpsink port=5000 host=127.0.0.1";
-    let pipeline = create_pipeline(launch)?;
+const VIDEO_SINK: &str = "video_sink";
+const AUDIO_SINK: &str = "audio_sink";
 
-    Ok(pipeline)
+// The picture videotestsrc generates
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TestPattern {
+    Smpte,
+    Snow,
+    Black,
+    White,
+    Ball,
+    Checkers,
+    Gradient,
 }
 
-pub fn start() -> (Sender<Bytes>, Receiver<Bytes>) {
-    let (send, recv) = channel::<Bytes>();
+impl TestPattern {
+    fn nick(&self) -> &'static str {
+        match self {
+            TestPattern::Smpte => "smpte",
+            TestPattern::Snow => "snow",
+            TestPattern::Black => "black",
+            TestPattern::White => "white",
+            TestPattern::Ball => "ball",
+            TestPattern::Checkers => "checkers-8",
+            TestPattern::Gradient => "gradient",
+        }
+    }
+}
+
+// An audio test tone sent next to the video, to its own port
+#[derive(Clone, Debug, PartialEq)]
+struct TestAudio {
+    codec: AudioCodec,
+    port: u16,
+}
+
+// The RTP stream a test server sends.  Encoders put a keyframe every second,
+// so recordings of the stream can be split into segments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestSourceConfig {
+    width: u32,
+    height: u32,
+    framerate: u32,
+    pattern: TestPattern,
+    codec: VideoCodec,
+
+    // kbit/s
+    bitrate: u32,
+
+    // None for the codec's default
+    payload_type: Option<u8>,
+
+    // None for a random SSRC
+    ssrc: Option<u32>,
+
+    host: String,
+    port: u16,
+    audio: Option<TestAudio>,
+
+    // None for the audio codec's default
+    audio_payload_type: Option<u8>,
+
+    // None to send until stopped
+    num_buffers: Option<u32>,
+}
+
+impl Default for TestSourceConfig {
+    fn default() -> Self {
+        TestSourceConfig {
+            width: 1280,
+            height: 720,
+            framerate: 30,
+            pattern: TestPattern::Smpte,
+            codec: VideoCodec::H264,
+            bitrate: 2048,
+            payload_type: None,
+            ssrc: None,
+            host: "127.0.0.1".to_owned(),
+            port: 5000,
+            audio: None,
+            audio_payload_type: None,
+            num_buffers: None,
+        }
+    }
+}
+
+impl TestSourceConfig {
+    pub fn new() -> Self {
+        TestSourceConfig::default()
+    }
+
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    // Frames per second
+    pub fn framerate(mut self, framerate: u32) -> Self {
+        self.framerate = framerate;
+        self
+    }
+
+    pub fn pattern(mut self, pattern: TestPattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    // AV1 also needs the rtp plugin of gst-plugins-rs for rtpav1pay, starting
+    // the server fails with a MissingElementError without it
+    pub fn codec(mut self, codec: VideoCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    // Target bitrate of the video encoder in kbit/s
+    pub fn bitrate(mut self, bitrate: u32) -> Self {
+        self.bitrate = bitrate;
+        self
+    }
+
+    pub fn payload_type(mut self, payload_type: u8) -> Self {
+        self.payload_type = Some(payload_type);
+        self
+    }
+
+    pub fn ssrc(mut self, ssrc: u32) -> Self {
+        self.ssrc = Some(ssrc);
+        self
+    }
+
+    pub fn destination(mut self, host: &str, port: u16) -> Self {
+        self.host = host.to_owned();
+        self.port = port;
+        self
+    }
+
+    // Also send a test tone, to the same host
+    pub fn audio(mut self, codec: AudioCodec, port: u16) -> Self {
+        self.audio = Some(TestAudio { codec, port });
+        self
+    }
+
+    // The payload type of the audio, which has to be set with `audio`
+    pub fn audio_payload_type(mut self, payload_type: u8) -> Self {
+        self.audio_payload_type = Some(payload_type);
+        self
+    }
 
-    std::thread::spawn(|| {
-        match pipeline().and_then(main_loop_simple) {
-            Ok(r) => r,
-            Err(e) => log::error!("Error! {}", e),
+    // End the stream after this many video frames, the audio ends about the same time
+    pub fn num_buffers(mut self, num_buffers: u32) -> Self {
+        self.num_buffers = Some(num_buffers);
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        let error = |message: &str| Err(MediaPipelineError::TestSourceError(message.to_owned()));
+
+        if self.width == 0 || self.height == 0 {
+            return error("the resolution has to be at least 1x1");
+        }
+
+        if self.framerate == 0 {
+            return error("the framerate has to be at least 1");
+        }
+
+        // caps fields are gints
+        if [self.width, self.height, self.framerate]
+            .iter()
+            .any(|value| i32::try_from(*value).is_err())
+        {
+            return error("the resolution and framerate have to be at most 2147483647");
+        }
+
+        if self.bitrate == 0 {
+            return error("the bitrate has to be at least 1 kbit/s");
+        }
+
+        if self.encoder_bitrate().is_none() {
+            return error("the bitrate is too high for the encoder");
+        }
+
+        if self.audio.is_none() && self.audio_payload_type.is_some() {
+            return error("an audio payload type needs an audio stream");
+        }
+
+        for payload_type in self.payload_type.iter().chain(&self.audio_payload_type) {
+            if *payload_type > 127 {
+                return Err(MediaPipelineError::TestSourceError(format!(
+                    "payload type {} is above 127",
+                    payload_type
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The bitrate in the unit of the video encoder, None when it is out of
+    // the encoder's range.  The vpx encoders take bit/s as a gint, x264enc and
+    // x265enc kbit/s up to their own maximum.
+    fn encoder_bitrate(&self) -> Option<u32> {
+        match self.codec {
+            VideoCodec::H264 => Some(self.bitrate).filter(|bitrate| *bitrate <= 2_048_000),
+            VideoCodec::H265 => Some(self.bitrate).filter(|bitrate| *bitrate <= 102_400),
+            VideoCodec::Vp8 | VideoCodec::Vp9 => self
+                .bitrate
+                .checked_mul(1000)
+                .filter(|bitrate| i32::try_from(*bitrate).is_ok()),
+            VideoCodec::Av1 => Some(self.bitrate),
+        }
+    }
+
+    fn video_encoder(&self) -> ElementSpec {
+        let keyframe_interval = self.framerate;
+        let bitrate = self
+            .encoder_bitrate()
+            .expect("the bitrate is checked by validate");
+
+        match self.codec {
+            VideoCodec::H264 => ElementSpec::new("x264enc")
+                .property("tune", PropertyValue::Nick("zerolatency".into()))
+                .property("bitrate", bitrate)
+                .property("key-int-max", keyframe_interval),
+            VideoCodec::H265 => ElementSpec::new("x265enc")
+                .property("tune", PropertyValue::Nick("zerolatency".into()))
+                .property("bitrate", bitrate)
+                .property("key-int-max", keyframe_interval),
+            VideoCodec::Vp8 => ElementSpec::new("vp8enc")
+                .property("deadline", 1)
+                .property("target-bitrate", bitrate)
+                .property("keyframe-max-dist", keyframe_interval),
+            VideoCodec::Vp9 => ElementSpec::new("vp9enc")
+                .property("deadline", 1)
+                .property("target-bitrate", bitrate)
+                .property("keyframe-max-dist", keyframe_interval),
+            VideoCodec::Av1 => ElementSpec::new("av1enc")
+                .property("target-bitrate", bitrate)
+                .property("keyframe-max-dist", keyframe_interval),
+        }
+    }
+
+    fn video_payloader(&self) -> ElementSpec {
+        let factory = match self.codec {
+            VideoCodec::H264 => "rtph264pay",
+            VideoCodec::H265 => "rtph265pay",
+            VideoCodec::Vp8 => "rtpvp8pay",
+            VideoCodec::Vp9 => "rtpvp9pay",
+            VideoCodec::Av1 => "rtpav1pay",
         };
+        let payloader = ElementSpec::new(factory).property(
+            "pt",
+            self.payload_type
+                .unwrap_or_else(|| self.codec.default_payload_type()),
+        );
+
+        match self.ssrc {
+            Some(ssrc) => payloader.property("ssrc", ssrc),
+            None => payloader,
+        }
+    }
+
+    pub(crate) fn host(&self) -> &str {
+        &self.host
+    }
+
+    fn udpsink(&self, name: &str, port: u16) -> ElementSpec {
+        ElementSpec::new("udpsink")
+            .name(name)
+            .property("port", port)
+            .property("host", self.host.as_str())
+    }
+}
+
+// The test pattern encoded as RTP
+pub(crate) fn source_chain(config: &TestSourceConfig) -> Chain {
+    let mut source = ElementSpec::new("videotestsrc")
+        .property("pattern", PropertyValue::Nick(config.pattern.nick().into()));
+
+    if let Some(num_buffers) = config.num_buffers {
+        source = source.property("num-buffers", num_buffers);
+    }
+
+    Chain::new()
+        .element(source)
+        .caps(
+            CapsSpec::new("video/x-raw")
+                .field("format", "I420")
+                .field(
+                    "framerate",
+                    PropertyValue::Fraction(
+                        i32::try_from(config.framerate)
+                            .expect("the framerate is checked by validate"),
+                        1,
+                    ),
+                )
+                .field("width", PropertyValue::Int(config.width.into()))
+                .field("height", PropertyValue::Int(config.height.into())),
+        )
+        .element(config.video_encoder())
+        .element(config.video_payloader())
+}
+
+fn audio_chain(config: &TestSourceConfig, audio: &TestAudio) -> Chain {
+    let (encoder, payloader, channels) = match audio.codec {
+        AudioCodec::Opus => ("opusenc", "rtpopuspay", 2),
+        AudioCodec::Pcmu => ("mulawenc", "rtppcmupay", 1),
+        AudioCodec::Pcma => ("alawenc", "rtppcmapay", 1),
+    };
+    let mut source = ElementSpec::new("audiotestsrc");
+
+    // one audio buffer per video frame, so both streams end together
+    if let Some(num_buffers) = config.num_buffers {
+        source = source
+            .property("samplesperbuf", audio.codec.clock_rate() / config.framerate)
+            .property("num-buffers", num_buffers);
+    }
+
+    Chain::new()
+        .element(source)
+        .element(ElementSpec::new("audioconvert"))
+        .element(ElementSpec::new("audioresample"))
+        .caps(
+            CapsSpec::new("audio/x-raw")
+                .field("rate", PropertyValue::Int(audio.codec.clock_rate().into()))
+                .field("channels", channels),
+        )
+        .element(ElementSpec::new(encoder))
+        .element(
+            ElementSpec::new(payloader).property(
+                "pt",
+                config
+                    .audio_payload_type
+                    .unwrap_or_else(|| audio.codec.default_payload_type()),
+            ),
+        )
+}
+
+fn test_source_pipeline(config: &TestSourceConfig) -> Result<PipelineBuilder> {
+    sending_pipeline(config, |name, port| {
+        Chain::new().element(config.udpsink(name, port))
+    })
+}
+
+// The test streams sent through the chains a sink function makes, by the name
+// its sink has to have (to count what is sent) and the port it sends to
+pub(crate) fn sending_pipeline<F>(config: &TestSourceConfig, sink: F) -> Result<PipelineBuilder>
+where
+    F: Fn(&str, u16) -> Chain,
+{
+    config.validate()?;
+    config.codec.check_installed(
+        &Chain::new()
+            .element(config.video_encoder())
+            .element(config.video_payloader()),
+    )?;
+
+    let mut builder =
+        PipelineBuilder::new().chain(source_chain(config).append(sink(VIDEO_SINK, config.port)));
+
+    if let Some(audio) = &config.audio {
+        builder = builder.chain(audio_chain(config, audio).append(sink(AUDIO_SINK, audio.port)));
+    }
+
+    Ok(builder)
+}
+
+fn pipeline(config: &TestSourceConfig) -> Result<Pipeline> {
+    test_source_pipeline(config)?.build()
+}
+
+// How a test server ended
+#[derive(Debug)]
+pub enum ServerEvent {
+    // the source sent the buffers set by `TestSourceConfig::num_buffers`
+    Eos,
+
+    // the server was stopped through its handle
+    Stopped,
+
+    Error(MediaPipelineError),
+}
+
+// Count what the sinks send, buffer lists hold a packet per buffer
+fn count_sent(pipeline: &Pipeline, packets: &Arc<AtomicU64>, bytes: &Arc<AtomicU64>) {
+    for name in [VIDEO_SINK, AUDIO_SINK].iter() {
+        let pad = match pipeline
+            .by_name(name)
+            .and_then(|sink| sink.static_pad("sink"))
+        {
+            Some(pad) => pad,
+            None => continue,
+        };
+        let packets = packets.clone();
+        let bytes = bytes.clone();
+
+        pad.add_probe(
+            gstreamer::PadProbeType::BUFFER | gstreamer::PadProbeType::BUFFER_LIST,
+            move |_, info| {
+                let (count, size) = match &info.data {
+                    Some(gstreamer::PadProbeData::Buffer(buffer)) => (1, buffer.size()),
+                    Some(gstreamer::PadProbeData::BufferList(list)) => {
+                        (list.len(), list.iter().map(|buffer| buffer.size()).sum())
+                    }
+                    _ => return gstreamer::PadProbeReturn::Ok,
+                };
+
+                packets.fetch_add(count as u64, Ordering::Relaxed);
+                bytes.fetch_add(size as u64, Ordering::Relaxed);
+
+                gstreamer::PadProbeReturn::Ok
+            },
+        );
+    }
+}
+
+// A running test server.  Dropping the handle stops the server.
+pub struct TestServer {
+    pipeline: Pipeline,
+    events: Receiver<ServerEvent>,
+    stopping: Arc<AtomicBool>,
+    packets: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    // The event ending the server, sent once
+    pub fn events(&self) -> &Receiver<ServerEvent> {
+        &self.events
+    }
+
+    // RTP packets sent, video and audio
+    pub fn packets_sent(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    // Size of the RTP packets sent, headers included.  For the TCP test
+    // server it also counts the two byte length framing each packet.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    // The local port the video is sent from, None until the socket is bound
+    // (at the latest when the first packet is sent) and for TCP servers
+    pub fn source_port(&self) -> Option<u16> {
+        let socket = self
+            .pipeline
+            .by_name(VIDEO_SINK)?
+            .property("used-socket")
+            .ok()?
+            .get::<Option<gio::Socket>>()
+            .ok()??;
+        let address = socket
+            .local_address()
+            .ok()?
+            .downcast::<gio::InetSocketAddress>()
+            .ok()?;
+
+        match address.port() {
+            0 => None,
+            port => Some(port),
+        }
+    }
+
+    // Stop sending and wait until the pipeline is shut down
+    pub fn stop(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let worker = match self.worker.take() {
+            Some(worker) => worker,
+            None => return Ok(()),
+        };
+
+        self.stopping.store(true, Ordering::SeqCst);
+
+        // ends the bus loop of the pipeline, which then shuts the pipeline down
+        if let Some(bus) = self.pipeline.bus() {
+            if bus.post(&gstreamer::message::Eos::new()).is_err() {
+                log::info!("Test server has already ended");
+            }
+        }
+
+        worker.join().map_err(|_| {
+            MediaPipelineError::StopError("the test server thread panicked".to_owned())
+        })
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Err(error) = self.finish() {
+            log::error!("Error stopping a dropped test server: {}", error);
+        }
+    }
+}
+
+pub fn start() -> Result<TestServer> {
+    start_with_config(TestSourceConfig::default())
+}
+
+// Send the stream described by the config until it ends or is stopped
+pub fn start_with_config(config: TestSourceConfig) -> Result<TestServer> {
+    pipeline(&config).and_then(start_pipeline)
+}
+
+// Run a pipeline built by `sending_pipeline` in the background
+pub(crate) fn start_pipeline(pipeline: Pipeline) -> Result<TestServer> {
+    let (events_sender, events_receiver) = unbounded::<ServerEvent>();
+    let stopping = Arc::new(AtomicBool::new(false));
+    let packets = Arc::new(AtomicU64::new(0));
+    let bytes = Arc::new(AtomicU64::new(0));
+
+    count_sent(&pipeline, &packets, &bytes);
+
+    let worker_pipeline = pipeline.clone();
+    let worker_stopping = stopping.clone();
+    let worker = std::thread::spawn(move || {
+        let event = match main_loop_simple(worker_pipeline) {
+            Ok(()) if worker_stopping.load(Ordering::SeqCst) => ServerEvent::Stopped,
+            Ok(()) => ServerEvent::Eos,
+            Err(error) => {
+                log::error!("Error! {}", error);
+                ServerEvent::Error(error)
+            }
+        };
+
+        // not an error, nobody may be listening
+        let _ = events_sender.send(event);
     });
 
-    (send, recv)
+    Ok(TestServer {
+        pipeline,
+        events: events_receiver,
+        stopping,
+        packets,
+        bytes,
+        worker: Some(worker),
+    })
 }
 
 #[cfg(test)]
@@ -35,8 +565,109 @@ mod tests {
     fn it_serves_rtp_via_udp() {
         env_logger::try_init().ok();
 
-        let (_tx, rx) = start();
+        let server = start().unwrap();
+
+        while server.events().recv().is_ok() {}
+    }
+
+    #[test]
+    fn it_builds_test_sources() {
+        let config = TestSourceConfig::new()
+            .resolution(320, 240)
+            .framerate(15)
+            .pattern(TestPattern::Ball)
+            .codec(VideoCodec::Vp8)
+            .bitrate(500)
+            .payload_type(100)
+            .ssrc(1234)
+            .destination("10.0.0.2", 6000)
+            .audio(AudioCodec::Pcmu, 6002);
+
+        let launch = test_source_pipeline(&config).unwrap().launch().unwrap();
+
+        assert!(launch.starts_with(
+            "videotestsrc pattern=ball ! \
+            video/x-raw, format=(string)I420, framerate=(fraction)15/1, width=(int)320, height=(int)240 ! \
+            vp8enc deadline=1 target-bitrate=500000 keyframe-max-dist=15 ! \
+            rtpvp8pay pt=100 ssrc=1234 ! udpsink name=video_sink port=6000 host=10.0.0.2"
+        ));
+        assert!(launch.contains(
+            "mulawenc ! rtppcmupay pt=0 ! udpsink name=audio_sink port=6002 host=10.0.0.2"
+        ));
+
+        // the audio payload type is kept whichever is set first
+        let payload_type_first = TestSourceConfig::new()
+            .audio_payload_type(96)
+            .audio(AudioCodec::Opus, 6002);
+
+        assert!(test_source_pipeline(&payload_type_first)
+            .unwrap()
+            .launch()
+            .unwrap()
+            .contains("rtpopuspay pt=96"));
+
+        assert!(test_source_pipeline(&TestSourceConfig::new().framerate(0)).is_err());
+        assert!(test_source_pipeline(&TestSourceConfig::new().resolution(1 << 31, 240)).is_err());
+        assert!(test_source_pipeline(
+            &TestSourceConfig::new()
+                .codec(VideoCodec::Vp8)
+                .bitrate(u32::MAX)
+        )
+        .is_err());
+        assert!(test_source_pipeline(
+            &TestSourceConfig::new()
+                .codec(VideoCodec::Vp9)
+                .bitrate(2_147_484)
+        )
+        .is_err());
+        assert!(test_source_pipeline(&TestSourceConfig::new().bitrate(2_048_001)).is_err());
+        assert!(test_source_pipeline(
+            &TestSourceConfig::new()
+                .codec(VideoCodec::H265)
+                .bitrate(102_401)
+        )
+        .is_err());
+        assert!(test_source_pipeline(&TestSourceConfig::new().payload_type(128)).is_err());
+        assert!(test_source_pipeline(&TestSourceConfig::new().audio_payload_type(8)).is_err());
+    }
+
+    #[test]
+    fn it_checks_for_av1_plugins_up_front() {
+        let config = TestSourceConfig::new().codec(VideoCodec::Av1);
+
+        match test_source_pipeline(&config) {
+            Ok(_) => assert!(gstreamer::ElementFactory::find("rtpav1pay").is_some()),
+            Err(MediaPipelineError::MissingElementError(message)) => {
+                assert!(message.contains("gst-plugins-rs"))
+            }
+            Err(error) => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn it_ends_after_num_buffers() {
+        let config = TestSourceConfig::new()
+            .resolution(320, 240)
+            .destination("127.0.0.1", 5010)
+            .num_buffers(15);
+        let server = start_with_config(config).unwrap();
+
+        assert!(matches!(server.events().recv(), Ok(ServerEvent::Eos)));
+        assert!(server.packets_sent() >= 15);
+        assert!(server.bytes_sent() > server.packets_sent() * 12);
+    }
+
+    #[test]
+    fn it_stops_the_server() {
+        let server =
+            start_with_config(TestSourceConfig::new().destination("127.0.0.1", 5012)).unwrap();
+        let events = server.events().clone();
+
+        std::thread::sleep(std::time::Duration::from_millis(500));
 
-        while let Ok(_bytes) = rx.recv() {}
+        assert!(server.packets_sent() > 0);
+        assert!(server.source_port().is_some());
+        assert!(server.stop().is_ok());
+        assert!(matches!(events.recv(), Ok(ServerEvent::Stopped)));
     }
 }
@@ -0,0 +1,340 @@
+use crate::error::{MediaPipelineError, Result};
+use crate::pipeline_builder::{is_identifier, CapsSpec};
+use std::str::FromStr;
+
+// A parsed SDP document (RFC 4566), limited to what is needed to receive RTP
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionDescription {
+    pub media: Vec<MediaDescription>,
+}
+
+// A single `m=` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaDescription {
+    pub media: String,
+    pub port: u16,
+    pub protocol: String,
+
+    // empty for sections not carrying RTP, e.g. a WebRTC data channel
+    pub formats: Vec<RtpFormat>,
+}
+
+// A payload type of a media section, combining its `rtpmap` and `fmtp` attributes
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpFormat {
+    pub media: String,
+    pub payload_type: u8,
+    pub encoding_name: String,
+    pub clock_rate: u32,
+    pub encoding_params: Option<String>,
+    pub fmtp: Vec<(String, String)>,
+}
+
+impl SessionDescription {
+    pub fn parse(sdp: &str) -> Result<Self> {
+        let mut session = SessionDescription::default();
+
+        for line in sdp.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (kind, value) = line
+                .split_once('=')
+                .ok_or_else(|| sdp_error(format!("malformed line {:?}", line)))?;
+
+            match kind {
+                "m" => session.media.push(MediaDescription::parse(value)?),
+                "a" => {
+                    // session level attributes are not needed to receive RTP
+                    if let Some(media) = session.media.last_mut() {
+                        media.parse_attribute(value)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if session.media.is_empty() {
+            return Err(sdp_error("no media sections".to_owned()));
+        }
+
+        Ok(session)
+    }
+
+    // The first media section of a kind, e.g. "video" or "audio"
+    pub fn media(&self, media: &str) -> Option<&MediaDescription> {
+        self.media
+            .iter()
+            .find(|description| description.media == media)
+    }
+}
+
+impl FromStr for SessionDescription {
+    type Err = MediaPipelineError;
+
+    fn from_str(sdp: &str) -> Result<Self> {
+        SessionDescription::parse(sdp)
+    }
+}
+
+impl MediaDescription {
+    // Parses the value of `m=<media> <port>[/<count>] <proto> <fmt> ...`
+    fn parse(value: &str) -> Result<Self> {
+        let mut fields = value.split_whitespace();
+        let malformed = || sdp_error(format!("malformed media line {:?}", value));
+
+        let media = fields.next().ok_or_else(malformed)?.to_owned();
+        let port = fields
+            .next()
+            .and_then(|port| port.split('/').next())
+            .and_then(|port| port.parse::<u16>().ok())
+            .ok_or_else(malformed)?;
+        let protocol = fields.next().ok_or_else(malformed)?.to_owned();
+        let payload_types = fields
+            .map(|payload_type| payload_type.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>();
+
+        // sections not carrying RTP (e.g. a WebRTC data channel) are kept
+        // without formats
+        let formats = match payload_types {
+            Some(payload_types) if protocol.contains("RTP/") => payload_types
+                .into_iter()
+                .map(|payload_type| RtpFormat::new(&media, payload_type))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(MediaDescription {
+            media,
+            port,
+            protocol,
+            formats,
+        })
+    }
+
+    fn parse_attribute(&mut self, value: &str) -> Result<()> {
+        if self.formats.is_empty() {
+            return Ok(());
+        }
+
+        let (name, value) = match value.split_once(':') {
+            Some(attribute) => attribute,
+            None => return Ok(()),
+        };
+        let (payload_type, value) = match name {
+            "rtpmap" | "fmtp" => value
+                .split_once(' ')
+                .and_then(|(payload_type, value)| {
+                    payload_type.parse::<u8>().ok().map(|pt| (pt, value.trim()))
+                })
+                .ok_or_else(|| sdp_error(format!("malformed {} {:?}", name, value)))?,
+            _ => return Ok(()),
+        };
+        let format = match self.format_mut(payload_type) {
+            Some(format) => format,
+            None => return Ok(()),
+        };
+
+        if name == "rtpmap" {
+            let mut parts = value.split('/');
+            let malformed = || sdp_error(format!("malformed rtpmap {:?}", value));
+
+            format.encoding_name = parts.next().ok_or_else(malformed)?.to_uppercase();
+            format.clock_rate = parts
+                .next()
+                .and_then(|rate| rate.parse::<u32>().ok())
+                .ok_or_else(malformed)?;
+            format.encoding_params = parts.next().map(str::to_owned);
+        } else {
+            format.fmtp = value
+                .split(';')
+                .filter_map(|parameter| parameter.trim().split_once('='))
+                .map(|(key, value)| (key.to_lowercase(), value.to_owned()))
+                .collect();
+        }
+
+        Ok(())
+    }
+
+    // The first (preferred) format of the media section
+    pub fn format(&self) -> Option<&RtpFormat> {
+        self.formats.first()
+    }
+
+    fn format_mut(&mut self, payload_type: u8) -> Option<&mut RtpFormat> {
+        self.formats
+            .iter_mut()
+            .find(|format| format.payload_type == payload_type)
+    }
+}
+
+impl RtpFormat {
+    // Static payload types (RFC 3551) need no rtpmap, dynamic ones get one later
+    fn new(media: &str, payload_type: u8) -> Self {
+        let (encoding_name, clock_rate) = match payload_type {
+            0 => ("PCMU", 8000),
+            8 => ("PCMA", 8000),
+            _ => ("", 0),
+        };
+
+        RtpFormat {
+            media: media.to_owned(),
+            payload_type,
+            encoding_name: encoding_name.to_owned(),
+            clock_rate,
+            encoding_params: None,
+            fmtp: Vec::new(),
+        }
+    }
+
+    // Caps describing the RTP stream, with fmtp parameters as extra fields
+    // the way GStreamer's own SDP elements expose them
+    pub fn caps(&self) -> CapsSpec {
+        self.caps_of("application/x-rtp")
+    }
+
+    // Caps describing the stream encrypted with SRTP
+    pub fn srtp_caps(&self) -> CapsSpec {
+        self.caps_of("application/x-srtp")
+    }
+
+    // Caps describing the stream received over TCP, each packet framed by its
+    // length (RFC 4571)
+    pub(crate) fn framed_caps(&self, srtp: bool) -> CapsSpec {
+        if srtp {
+            self.caps_of("application/x-srtp-stream")
+        } else {
+            self.caps_of("application/x-rtp-stream")
+        }
+    }
+
+    fn caps_of(&self, media_type: &str) -> CapsSpec {
+        let mut caps = CapsSpec::new(media_type)
+            .field("media", self.media.as_str())
+            .field("clock-rate", i64::from(self.clock_rate))
+            .field("encoding-name", self.encoding_name.as_str())
+            .field("payload", i64::from(self.payload_type));
+
+        if let Some(encoding_params) = &self.encoding_params {
+            caps = caps.field("encoding-params", encoding_params);
+        }
+
+        for (key, value) in self.fmtp.iter().filter(|(key, _)| is_identifier(key)) {
+            caps = caps.field(key, value);
+        }
+
+        caps
+    }
+}
+
+fn sdp_error(message: String) -> MediaPipelineError {
+    MediaPipelineError::SdpError(message)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::record::RecordOptions;
+
+    const SDP: &str = "v=0\r
+o=- 0 0 IN IP4 127.0.0.1\r
+s=Media Pipeline\r
+c=IN IP4 127.0.0.1\r
+t=0 0\r
+a=tool:test\r
+m=audio 5002 RTP/AVP 111 0\r
+a=rtpmap:111 opus/48000/2\r
+m=video 5000 RTP/AVP 102\r
+a=rtpmap:102 H264/90000\r
+a=fmtp:102 packetization-mode=1;profile-level-id=42e01f;sprop-parameter-sets=Z0LAH9kAUAW7AWoCAgKAAAADAIAAAB5HjBkk,aMuMsg==\r
+";
+
+    #[test]
+    fn it_parses_media_sections() {
+        let sdp = SDP.parse::<SessionDescription>().unwrap();
+        let video = sdp.media("video").unwrap();
+        let audio = sdp.media("audio").unwrap();
+
+        assert_eq!(video.port, 5000);
+        assert_eq!(video.protocol, "RTP/AVP");
+        assert_eq!(
+            video.format(),
+            Some(&RtpFormat {
+                media: "video".to_owned(),
+                payload_type: 102,
+                encoding_name: "H264".to_owned(),
+                clock_rate: 90000,
+                encoding_params: None,
+                fmtp: vec![
+                    ("packetization-mode".to_owned(), "1".to_owned()),
+                    ("profile-level-id".to_owned(), "42e01f".to_owned()),
+                    (
+                        "sprop-parameter-sets".to_owned(),
+                        "Z0LAH9kAUAW7AWoCAgKAAAADAIAAAB5HjBkk,aMuMsg==".to_owned()
+                    ),
+                ],
+            })
+        );
+        assert_eq!(audio.formats.len(), 2);
+        assert_eq!(audio.formats[0].encoding_name, "OPUS");
+        assert_eq!(audio.formats[0].encoding_params.as_deref(), Some("2"));
+        assert_eq!(audio.formats[1].encoding_name, "PCMU");
+        assert_eq!(audio.formats[1].clock_rate, 8000);
+    }
+
+    // a browser offer, bundled on one port with a data channel
+    const WEBRTC_SDP: &str = "v=0\r
+o=- 4611731400430051336 2 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0 1\r
+m=video 9 UDP/TLS/RTP/SAVPF 96 97\r
+c=IN IP4 0.0.0.0\r
+a=mid:0\r
+a=rtpmap:96 VP8/90000\r
+a=rtpmap:97 rtx/90000\r
+a=fmtp:97 apt=96\r
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r
+c=IN IP4 0.0.0.0\r
+a=mid:1\r
+a=sctp-port:5000\r
+a=max-message-size:262144\r
+";
+
+    #[test]
+    fn it_keeps_sections_without_rtp() {
+        let sdp = SessionDescription::parse(WEBRTC_SDP).unwrap();
+        let application = sdp.media("application").unwrap();
+
+        assert_eq!(application.protocol, "UDP/DTLS/SCTP");
+        assert!(application.formats.is_empty());
+        assert_eq!(application.format(), None);
+        assert_eq!(sdp.media("video").unwrap().formats.len(), 2);
+        assert_eq!(
+            RecordOptions::from_sdp(&sdp)
+                .unwrap()
+                .video_format()
+                .encoding_name,
+            "VP8"
+        );
+    }
+
+    #[test]
+    fn it_derives_caps() {
+        let sdp = SessionDescription::parse(SDP).unwrap();
+        let caps = sdp.media("video").unwrap().format().unwrap().caps();
+
+        assert_eq!(
+            caps.to_string(),
+            "application/x-rtp, media=(string)video, clock-rate=(int)90000, \
+            encoding-name=(string)H264, payload=(int)102, packetization-mode=(string)1, \
+            profile-level-id=(string)42e01f, \
+            sprop-parameter-sets=(string)\"Z0LAH9kAUAW7AWoCAgKAAAADAIAAAB5HjBkk,aMuMsg==\""
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_sdp() {
+        assert!(SessionDescription::parse("v=0").is_err());
+        assert!(SessionDescription::parse("m=video abc RTP/AVP 96").is_err());
+        assert!(SessionDescription::parse("m=video 5000 RTP/AVP 96\na=rtpmap:96 H264").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use crate::error::{MediaPipelineError, Result};
+use crate::pipeline_builder::{Chain, ElementSpec};
+use crate::record::RecordOptions;
+use crate::sdp::RtpFormat;
+use gstreamer::prelude::*;
+use gstreamer::Element;
+use gstreamer::Pipeline;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Names of the decoders of the received streams
+const VIDEO_SRTPDEC: &str = "video_srtpdec";
+const AUDIO_SRTPDEC: &str = "audio_srtpdec";
+
+// Rejected packets of a source are reported at most this often
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// The element message a decoder posts about rejected packets
+const REJECTED_MESSAGE: &str = "srtp-rejected";
+
+// The decoder of a stream's media, "video" or "audio"
+pub(crate) fn decoder_name(media: &str) -> &'static str {
+    match media {
+        "audio" => AUDIO_SRTPDEC,
+        _ => VIDEO_SRTPDEC,
+    }
+}
+
+// The crypto suites of SRTP (RFC 3711, RFC 6188, RFC 7714).  RTCP of a stream
+// is protected with the same suite.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SrtpSuite {
+    AesCm128HmacSha1_80,
+    AesCm128HmacSha1_32,
+    AesCm256HmacSha1_80,
+    AesCm256HmacSha1_32,
+    AeadAes128Gcm,
+    AeadAes256Gcm,
+}
+
+impl SrtpSuite {
+    fn cipher(&self) -> &'static str {
+        match self {
+            SrtpSuite::AesCm128HmacSha1_80 | SrtpSuite::AesCm128HmacSha1_32 => "aes-128-icm",
+            SrtpSuite::AesCm256HmacSha1_80 | SrtpSuite::AesCm256HmacSha1_32 => "aes-256-icm",
+            SrtpSuite::AeadAes128Gcm => "aes-128-gcm",
+            SrtpSuite::AeadAes256Gcm => "aes-256-gcm",
+        }
+    }
+
+    // GCM authenticates with the cipher
+    fn auth(&self) -> &'static str {
+        match self {
+            SrtpSuite::AesCm128HmacSha1_80 | SrtpSuite::AesCm256HmacSha1_80 => "hmac-sha1-80",
+            SrtpSuite::AesCm128HmacSha1_32 | SrtpSuite::AesCm256HmacSha1_32 => "hmac-sha1-32",
+            SrtpSuite::AeadAes128Gcm | SrtpSuite::AeadAes256Gcm => "null",
+        }
+    }
+
+    // Master key and salt lengths in bytes
+    fn key_lengths(&self) -> (usize, usize) {
+        match self {
+            SrtpSuite::AesCm128HmacSha1_80 | SrtpSuite::AesCm128HmacSha1_32 => (16, 14),
+            SrtpSuite::AesCm256HmacSha1_80 | SrtpSuite::AesCm256HmacSha1_32 => (32, 14),
+            SrtpSuite::AeadAes128Gcm => (16, 12),
+            SrtpSuite::AeadAes256Gcm => (32, 12),
+        }
+    }
+}
+
+// A pre-shared master key and salt
+#[derive(Clone, PartialEq)]
+pub struct SrtpKey {
+    suite: SrtpSuite,
+    master_key: Vec<u8>,
+    master_salt: Vec<u8>,
+}
+
+// Keeps the key material out of logs
+impl fmt::Debug for SrtpKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrtpKey")
+            .field("suite", &self.suite)
+            .field("master_key", &"<redacted>")
+            .field("master_salt", &"<redacted>")
+            .finish()
+    }
+}
+
+impl SrtpKey {
+    pub fn new(suite: SrtpSuite, master_key: &[u8], master_salt: &[u8]) -> Self {
+        SrtpKey {
+            suite,
+            master_key: master_key.to_vec(),
+            master_salt: master_salt.to_vec(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let (key_length, salt_length) = self.suite.key_lengths();
+        let invalid = |what: &str, length: usize| {
+            Err(MediaPipelineError::RecordOptionsError(format!(
+                "SRTP {:?} needs a {} byte master {}",
+                self.suite, length, what
+            )))
+        };
+
+        if self.master_key.len() != key_length {
+            return invalid("key", key_length);
+        }
+
+        if self.master_salt.len() != salt_length {
+            return invalid("salt", salt_length);
+        }
+
+        Ok(())
+    }
+
+    // The caps srtpdec asks for when a source starts sending
+    fn caps(&self) -> gstreamer::Caps {
+        let key = [self.master_key.as_slice(), self.master_salt.as_slice()].concat();
+
+        gstreamer::Caps::builder("application/x-srtp")
+            .field("srtp-key", gstreamer::Buffer::from_slice(key))
+            .field("srtp-cipher", self.suite.cipher())
+            .field("srtp-auth", self.suite.auth())
+            .field("srtcp-cipher", self.suite.cipher())
+            .field("srtcp-auth", self.suite.auth())
+            .build()
+    }
+}
+
+// Decryption of a stream received as SRTP, with a key for its sources and
+// optionally other keys for some of them
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrtpOptions {
+    key: SrtpKey,
+    ssrc_keys: HashMap<u32, SrtpKey>,
+}
+
+impl SrtpOptions {
+    pub fn new(key: SrtpKey) -> Self {
+        SrtpOptions {
+            key,
+            ssrc_keys: HashMap::new(),
+        }
+    }
+
+    // Decrypt the packets of a source (SSRC) with another key
+    pub fn ssrc_key(mut self, ssrc: u32, key: SrtpKey) -> Self {
+        self.ssrc_keys.insert(ssrc, key);
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        std::iter::once(&self.key)
+            .chain(self.ssrc_keys.values())
+            .try_for_each(SrtpKey::validate)
+    }
+
+    fn key(&self, ssrc: u32) -> &SrtpKey {
+        self.ssrc_keys.get(&ssrc).unwrap_or(&self.key)
+    }
+}
+
+// Decrypt what a source produces as SRTP of a format, before anything else
+// looks at the packets
+pub(crate) fn decrypted_chain(source: Chain, format: &RtpFormat) -> Chain {
+    source
+        .caps(format.srtp_caps())
+        .element(ElementSpec::new("srtpdec").name(decoder_name(&format.media)))
+        .caps(format.caps())
+}
+
+// Packets of a source srtpdec dropped since the recording started, because
+// they failed authentication (most likely a wrong key) or were replayed
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SrtpRejection {
+    // "video" or "audio"
+    pub media: String,
+    pub ssrc: u32,
+    pub packets: u64,
+}
+
+impl SrtpRejection {
+    fn structure(&self) -> gstreamer::Structure {
+        gstreamer::Structure::builder(REJECTED_MESSAGE)
+            .field("media", self.media.as_str())
+            .field("ssrc", self.ssrc)
+            .field("packets", self.packets)
+            .build()
+    }
+
+    // The rejection an element message is about, if it is about one
+    pub(crate) fn from_structure(structure: Option<&gstreamer::StructureRef>) -> Option<Self> {
+        let structure = structure.filter(|s| s.name() == REJECTED_MESSAGE)?;
+
+        Some(SrtpRejection {
+            media: structure.get::<String>("media").ok()?,
+            ssrc: structure.get::<u32>("ssrc").ok()?,
+            packets: structure.get::<u64>("packets").ok()?,
+        })
+    }
+}
+
+// srtpdec drops packets it cannot decrypt without telling, so each packet
+// going in has to come out before the next one goes in
+#[derive(Default)]
+struct Rejections {
+    // SSRC of the packet being decrypted
+    pending: Option<u32>,
+
+    // rejected packets and when they were last reported, by SSRC
+    sources: HashMap<u32, (u64, Option<Instant>)>,
+}
+
+impl Rejections {
+    // A packet of a source goes in, returns the source and its rejected
+    // packets if the previous packet did not come out and is to be reported
+    fn received(&mut self, ssrc: Option<u32>, now: Instant) -> Option<(u32, u64)> {
+        let previous = std::mem::replace(&mut self.pending, ssrc)?;
+        let (packets, reported) = self.sources.entry(previous).or_insert((0, None));
+
+        *packets += 1;
+
+        match reported {
+            Some(at) if now.saturating_duration_since(*at) < REPORT_INTERVAL => None,
+            _ => {
+                *reported = Some(now);
+                Some((previous, *packets))
+            }
+        }
+    }
+
+    fn decrypted(&mut self) {
+        self.pending = None;
+    }
+}
+
+// The SSRC of an (S)RTP packet, whose header is not encrypted
+fn ssrc(packet: &[u8]) -> Option<u32> {
+    match packet {
+        [_, _, _, _, _, _, _, _, a, b, c, d, ..] => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+        _ => None,
+    }
+}
+
+// Post an element message whenever packets of a source get rejected
+fn report_rejections(decoder: &Element, media: &str) -> Result<()> {
+    let pad = |name: &str| {
+        decoder
+            .static_pad(name)
+            .ok_or(MediaPipelineError::CreateElementError("srtpdec"))
+    };
+    let rejections = Arc::new(Mutex::new(Rejections::default()));
+    let received = rejections.clone();
+    let weak_decoder = decoder.downgrade();
+    let media = media.to_owned();
+
+    pad("rtp_sink")?.add_probe(gstreamer::PadProbeType::BUFFER, move |_, info| {
+        let packet_ssrc = match &info.data {
+            Some(gstreamer::PadProbeData::Buffer(buffer)) => {
+                buffer.map_readable().ok().and_then(|map| ssrc(&map))
+            }
+            _ => None,
+        };
+        let rejected = received
+            .lock()
+            .unwrap()
+            .received(packet_ssrc, Instant::now());
+
+        if let (Some((ssrc, packets)), Some(decoder)) = (rejected, weak_decoder.upgrade()) {
+            let rejection = SrtpRejection {
+                media: media.clone(),
+                ssrc,
+                packets,
+            };
+
+            if let Err(error) =
+                decoder.post_message(gstreamer::message::Element::new(rejection.structure()))
+            {
+                log::error!("Could not report {:?}: {}", rejection, error);
+            }
+        }
+
+        gstreamer::PadProbeReturn::Ok
+    });
+
+    pad("rtp_src")?.add_probe(gstreamer::PadProbeType::BUFFER, move |_, _| {
+        rejections.lock().unwrap().decrypted();
+
+        gstreamer::PadProbeReturn::Ok
+    });
+
+    Ok(())
+}
+
+// Hand the keys to the decoders of the streams the pipeline receives as SRTP
+pub(crate) fn attach_srtp(pipeline: &Pipeline, options: &RecordOptions) -> Result<()> {
+    let streams = [
+        ("video", options.srtp_options()),
+        ("audio", options.audio_srtp_options()),
+    ];
+
+    for (media, srtp) in streams.iter() {
+        let (srtp, decoder) = match (srtp, pipeline.by_name(decoder_name(media))) {
+            (Some(srtp), Some(decoder)) => ((*srtp).clone(), decoder),
+            _ => continue,
+        };
+
+        decoder.connect("request-key", false, move |values| {
+            let key = match values.get(1).and_then(|ssrc| ssrc.get::<u32>().ok()) {
+                Some(ssrc) => srtp.key(ssrc),
+                None => &srtp.key,
+            };
+
+            Some(key.caps().to_value())
+        })?;
+
+        report_rejections(&decoder, media)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_validates_srtp_keys() {
+        let key = SrtpKey::new(SrtpSuite::AesCm128HmacSha1_80, &[1; 16], &[2; 14]);
+
+        assert!(SrtpOptions::new(key.clone()).validate().is_ok());
+        assert!(SrtpOptions::new(key.clone())
+            .ssrc_key(
+                0x2a,
+                SrtpKey::new(SrtpSuite::AeadAes256Gcm, &[3; 32], &[4; 14])
+            )
+            .validate()
+            .is_err());
+        assert!(
+            SrtpKey::new(SrtpSuite::AesCm256HmacSha1_32, &[1; 16], &[2; 14])
+                .validate()
+                .is_err()
+        );
+        assert!(!format!("{:?}", key).contains("[1"));
+    }
+
+    #[test]
+    fn it_reports_rejected_packets() {
+        let start = Instant::now();
+        let mut rejections = Rejections::default();
+
+        assert_eq!(rejections.received(Some(1), start), None);
+        rejections.decrypted();
+        assert_eq!(rejections.received(Some(2), start), None);
+
+        // the packet of 2 did not come out
+        assert_eq!(rejections.received(Some(2), start), Some((2, 1)));
+        assert_eq!(rejections.received(Some(2), start), None);
+        assert_eq!(
+            rejections.received(Some(1), start + REPORT_INTERVAL),
+            Some((2, 3))
+        );
+        rejections.decrypted();
+        assert_eq!(rejections.received(None, start), None);
+    }
+
+    #[test]
+    fn it_reads_rejection_messages() {
+        gstreamer::init().unwrap();
+
+        let rejection = SrtpRejection {
+            media: "video".to_owned(),
+            ssrc: 0x2a,
+            packets: 3,
+        };
+
+        assert_eq!(
+            SrtpRejection::from_structure(Some(&*rejection.structure())),
+            Some(rejection)
+        );
+        assert_eq!(
+            SrtpRejection::from_structure(Some(&*gstreamer::Structure::new_empty("other"))),
+            None
+        );
+    }
+}
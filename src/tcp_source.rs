@@ -0,0 +1,122 @@
+use crate::error::{MediaPipelineError, Result};
+use crate::pipeline_builder::{Chain, ElementSpec};
+use crate::sdp::RtpFormat;
+use std::net::SocketAddr;
+
+// How an RTP over TCP connection is set up, by the side it is set up on
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpMode {
+    // wait for the other side to connect to a local address
+    Listen,
+
+    // connect to the other side listening on an address
+    Connect,
+}
+
+// Where a stream is received over TCP, each packet framed by its length
+// (RFC 4571).  A connection carries one stream, audio needs one of its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TcpSourceConfig {
+    mode: TcpMode,
+    address: SocketAddr,
+}
+
+impl TcpSourceConfig {
+    // Wait for the sender to connect to a local address
+    pub fn listen(address: SocketAddr) -> Self {
+        TcpSourceConfig {
+            mode: TcpMode::Listen,
+            address,
+        }
+    }
+
+    // Connect to a sender listening on an address, it has to be listening
+    // when the recording starts
+    pub fn connect(address: SocketAddr) -> Self {
+        TcpSourceConfig {
+            mode: TcpMode::Connect,
+            address,
+        }
+    }
+
+    pub fn mode(&self) -> TcpMode {
+        self.mode
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Listening needs a port, connecting a host as well
+    pub(crate) fn validate(&self) -> Result<()> {
+        let error = |message: String| Err(MediaPipelineError::RecordOptionsError(message));
+
+        if self.address.port() == 0 {
+            return error("TCP source needs a port".to_owned());
+        }
+
+        if self.mode == TcpMode::Connect && self.address.ip().is_unspecified() {
+            return error(format!(
+                "TCP source cannot connect to {}",
+                self.address.ip()
+            ));
+        }
+
+        Ok(())
+    }
+
+    // The TCP source and the depayloader taking the packets out of their
+    // frames, RTP or (encrypted) SRTP of a format.  Packets are timestamped
+    // as they arrive, like the ones of a UDP source.
+    pub(crate) fn chain(&self, format: &RtpFormat, srtp: bool) -> Chain {
+        let factory = match self.mode {
+            TcpMode::Listen => "tcpserversrc",
+            TcpMode::Connect => "tcpclientsrc",
+        };
+
+        Chain::new()
+            .element(
+                ElementSpec::new(factory)
+                    .property("host", self.address.ip().to_string())
+                    .property("port", self.address.port())
+                    .property("do-timestamp", true),
+            )
+            .caps(format.framed_caps(srtp))
+            .element(ElementSpec::new("rtpstreamdepay"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::record::RecordOptions;
+
+    #[test]
+    fn it_configures_tcp_sources() {
+        let format = RecordOptions::new().video_format();
+        let listen = TcpSourceConfig::listen("0.0.0.0:5020".parse().unwrap());
+
+        assert!(listen.validate().is_ok());
+        assert_eq!(
+            listen.chain(&format, false).to_string(),
+            "tcpserversrc host=0.0.0.0 port=5020 do-timestamp=true ! \
+            application/x-rtp-stream, media=(string)video, clock-rate=(int)90000, \
+            encoding-name=(string)H264, payload=(int)96 ! rtpstreamdepay"
+        );
+
+        let connect = TcpSourceConfig::connect("[::1]:5020".parse().unwrap());
+
+        assert!(connect.validate().is_ok());
+        assert!(connect.chain(&format, true).to_string().starts_with(
+            "tcpclientsrc host=::1 port=5020 do-timestamp=true ! application/x-srtp-stream"
+        ));
+
+        assert!(TcpSourceConfig::connect("0.0.0.0:5020".parse().unwrap())
+            .validate()
+            .is_err());
+        assert!(TcpSourceConfig::listen("0.0.0.0:0".parse().unwrap())
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::error::{MediaPipelineError, Result};
+use crate::pipeline_builder::ElementSpec;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+// Where a stream is received over UDP: the address and port to bind to, or a
+// multicast group and its port to join
+#[derive(Clone, Debug, PartialEq)]
+pub struct UdpSourceConfig {
+    address: SocketAddr,
+    multicast_iface: Option<String>,
+    multicast_sources: Vec<IpAddr>,
+    buffer_size: Option<u32>,
+}
+
+impl From<SocketAddr> for UdpSourceConfig {
+    fn from(address: SocketAddr) -> Self {
+        UdpSourceConfig::new(address)
+    }
+}
+
+impl UdpSourceConfig {
+    pub fn new(address: SocketAddr) -> Self {
+        UdpSourceConfig {
+            address,
+            multicast_iface: None,
+            multicast_sources: Vec::new(),
+            buffer_size: None,
+        }
+    }
+
+    // A port on all IPv4 interfaces
+    pub fn on_port(port: u16) -> Self {
+        UdpSourceConfig::new(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+    }
+
+    // A port given as a string, the way the port based recorders take it
+    pub(crate) fn from_port(port: &str) -> Result<Self> {
+        port.parse::<u16>()
+            .map(UdpSourceConfig::on_port)
+            .map_err(|_| udp_error(&format!("port {:?} is not a number", port)))
+    }
+
+    // The network interface to join the multicast group on, e.g. "eth1"
+    pub fn multicast_iface(mut self, iface: &str) -> Self {
+        self.multicast_iface = Some(iface.to_owned());
+        self
+    }
+
+    // Only take the group's packets from a sender (source-specific multicast,
+    // RFC 4607).  Can be called for several senders, needs GStreamer 1.24.
+    pub fn multicast_source(mut self, source: IpAddr) -> Self {
+        self.multicast_sources.push(source);
+        self
+    }
+
+    // Size of the socket's receive buffer in bytes, raise it for high bitrates
+    // to not lose packets in bursts.  The system may limit it (on Linux to
+    // net.core.rmem_max).
+    pub fn buffer_size(mut self, buffer_size: u32) -> Self {
+        self.buffer_size = Some(buffer_size);
+        self
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        let multicast = self.address.ip().is_multicast();
+
+        if self.address.port() == 0 {
+            return Err(udp_error("source needs a port"));
+        }
+
+        if !multicast && (self.multicast_iface.is_some() || !self.multicast_sources.is_empty()) {
+            return Err(udp_error(&format!(
+                "{} is not a multicast group",
+                self.address.ip()
+            )));
+        }
+
+        match self
+            .multicast_sources
+            .iter()
+            .find(|source| source.is_ipv4() != self.address.is_ipv4())
+        {
+            Some(source) => Err(udp_error(&format!(
+                "multicast source {} is not of the family of {}",
+                source,
+                self.address.ip()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // The udpsrc receiving the stream, the address left out when it is the
+    // default of all IPv4 interfaces
+    pub(crate) fn element(&self) -> ElementSpec {
+        let mut element = ElementSpec::new("udpsrc");
+
+        if self.address.ip() != IpAddr::from(Ipv4Addr::UNSPECIFIED) {
+            element = element.property("address", self.address.ip().to_string());
+        }
+
+        element = element.property("port", self.address.port());
+
+        if let Some(iface) = &self.multicast_iface {
+            element = element.property("multicast-iface", iface);
+        }
+
+        if !self.multicast_sources.is_empty() {
+            let sources = self
+                .multicast_sources
+                .iter()
+                .map(|source| format!("+{}", source))
+                .collect::<String>();
+
+            element = element.property("multicast-source", sources);
+        }
+
+        if let Some(buffer_size) = self.buffer_size {
+            element = element.property("buffer-size", buffer_size);
+        }
+
+        element
+    }
+}
+
+fn udp_error(message: &str) -> MediaPipelineError {
+    MediaPipelineError::RecordOptionsError(format!("UDP {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_configures_udp_sources() {
+        assert_eq!(
+            UdpSourceConfig::from_port("5000")
+                .unwrap()
+                .element()
+                .to_string(),
+            "udpsrc port=5000"
+        );
+        assert!(UdpSourceConfig::from_port("udp").is_err());
+
+        let multicast = UdpSourceConfig::new("239.1.1.1:5004".parse().unwrap())
+            .multicast_iface("eth1")
+            .multicast_source("10.0.0.5".parse().unwrap())
+            .multicast_source("10.0.0.6".parse().unwrap())
+            .buffer_size(4 * 1024 * 1024);
+
+        assert!(multicast.validate().is_ok());
+        assert_eq!(
+            multicast.element().to_string(),
+            "udpsrc address=239.1.1.1 port=5004 multicast-iface=eth1 \
+            multicast-source=+10.0.0.5+10.0.0.6 buffer-size=4194304"
+        );
+
+        let ipv6 = UdpSourceConfig::from("[ff15::1]:5004".parse::<SocketAddr>().unwrap());
+
+        assert_eq!(
+            ipv6.element().to_string(),
+            "udpsrc address=ff15::1 port=5004"
+        );
+        assert!(ipv6
+            .multicast_source("10.0.0.5".parse().unwrap())
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_udp_sources() {
+        assert!(UdpSourceConfig::on_port(0).validate().is_err());
+        assert!(UdpSourceConfig::on_port(5000)
+            .multicast_iface("eth1")
+            .validate()
+            .is_err());
+        assert!(UdpSourceConfig::new("10.0.0.1:5000".parse().unwrap())
+            .multicast_source("10.0.0.5".parse().unwrap())
+            .validate()
+            .is_err());
+        assert!(UdpSourceConfig::new("[::]:5000".parse().unwrap())
+            .buffer_size(1 << 20)
+            .validate()
+            .is_ok());
+    }
+}